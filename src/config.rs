@@ -0,0 +1,662 @@
+use crate::logging::LogTarget;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Headers that are never passed to scripts unless explicitly allowed, "Proxy" would become HTTP_PROXY
+const DEFAULT_SCRIPT_HEADER_DENY: &[&str] = &["Authorization", "Proxy-Authorization", "Proxy"];
+
+const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_PATH_DEPTH: usize = 64;
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_DECOMPRESSED_BODY: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_DECOMPRESSION_RATIO: u64 = 100;
+const DEFAULT_MAX_SCRIPT_HEADER_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_SCRIPT_HEADERS: usize = 100;
+const DEFAULT_REWRITE_TYPES: &[&str] = &["text/html"];
+// Images, audio, video, fonts and archives are already compressed, so they are left out
+const DEFAULT_GZIP_TYPES: &[&str] = &["text/", "application/json", "application/javascript", "application/xml", "application/wasm", "image/svg+xml"];
+
+pub const USAGE: &str = "Usage: rustwebserver PORT ROOT_FOLDER [OPTIONS]";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+// The body of error responses that don't have one of their own
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Empty,
+    // RFC 7807 application/problem+json
+    ProblemJson,
+}
+
+#[cfg(feature = "tracing")]
+#[derive(Clone, Copy)]
+pub enum TraceFormat {
+    Pretty,
+    Json,
+}
+
+pub struct Config {
+    pub port: String,
+    pub root_folder: PathBuf,
+    // Time allowed for resolving the root folder and binding the listener
+    pub startup_timeout: Duration,
+    // Disable Nagle's algorithm on client connections
+    pub tcp_nodelay: bool,
+    // How long an idle persistent connection waits for the first byte of the next request, zero disables keep-alive
+    pub keep_alive_timeout: Duration,
+    // Time allowed for receiving a request once it started, or the first one once connected, zero waits forever
+    pub request_timeout: Duration,
+    // Send Server: rustywebserver/VERSION with every response
+    pub server_header: bool,
+    // Serve /404.html from the root folder for missing files
+    pub not_found_page: bool,
+    // Paths with more segments than this get 400
+    pub max_path_depth: usize,
+    // Path prefixes and glob patterns answered with 403, see matches_path_pattern
+    pub forbidden_paths: Vec<String>,
+    // Answer 400 to requests without a User-Agent header
+    pub require_user_agent: bool,
+    // Path prefixes, like health checks, that are served without a User-Agent
+    pub user_agent_exempt: Vec<String>,
+    // Path prefixes and the media type their clients have to accept
+    pub required_accept: Vec<(String, String)>,
+    // Media types and the index file served for directories when the client's Accept header names them
+    pub index_rules: Vec<(String, String)>,
+    // Reject paths whose case doesn't match the names on disk
+    pub case_sensitive_paths: bool,
+    // Serve directory listings as JSON for ?format=json or Accept: application/json
+    pub json_listing: bool,
+    // Body given to error responses without one of their own
+    pub error_format: ErrorFormat,
+    // List the scripts directory on GET instead of answering 403
+    pub scripts_listing: bool,
+    // Extension methods that are run as scripts, like PROPFIND
+    pub script_methods: Vec<String>,
+    // When not empty, only these request headers are passed to scripts
+    pub script_header_allow: Vec<String>,
+    // Request headers that are not passed to scripts
+    pub script_header_deny: Vec<String>,
+    // URL path prefixes and the only request content types the scripts under them accept
+    pub script_content_types: Vec<(PathBuf, String)>,
+    // Status sent when a script succeeds without output, 204 or 200
+    pub empty_script_status: u16,
+    // URL path prefixes and the time the scripts under them have to start writing, or they are killed and answered with 504
+    pub script_first_byte_timeout: Vec<(PathBuf, Duration)>,
+    // URL path prefixes and the patch media types OPTIONS advertises in Accept-Patch for the scripts under them
+    pub accept_patch: Vec<(PathBuf, String)>,
+    // Scripts whose CGI header block is larger than this many bytes, or has more lines, are answered with 502
+    pub max_script_header_size: usize,
+    pub max_script_headers: usize,
+    // URL path prefixes and the environment variables set for the scripts under them
+    pub script_env: Vec<(PathBuf, String, String)>,
+    // Decode gzip request bodies before passing them to scripts
+    pub decompress_request_bodies: bool,
+    // Decoded request bodies larger than this, or than this many times their encoded size, get 413
+    pub max_decompressed_body: u64,
+    // 0 disables the ratio limit
+    pub max_decompression_ratio: u64,
+    // Send X-Content-Type-Options: nosniff with script output
+    pub script_nosniff: bool,
+    // URL path prefixes and the line ending the body piped to the scripts under them is converted to
+    pub script_stdin_newlines: Vec<(PathBuf, LineEnding)>,
+    // Origins (or "*") sent in Timing-Allow-Origin on static responses
+    pub timing_allow_origins: Vec<String>,
+    // Files open at once for static serving, 0 for unlimited
+    pub max_open_files: usize,
+    // Request bodies larger than this, as declared or as chunks arrive, get 413, 0 for unlimited
+    pub max_body_size: u64,
+    // Bytes per second static file bodies are sent at, 0 for unlimited
+    pub max_send_rate: u64,
+    // Requests served between closing and reopening the listener, 0 never reopens it
+    pub reset_listener_after: u64,
+    // Content codings and the roots holding files pre-compressed with them, mirroring the root folder
+    pub encoded_roots: Vec<(String, PathBuf)>,
+    // Compress text responses with gzip for clients accepting it
+    pub gzip: bool,
+    // Content type prefixes --gzip compresses
+    pub gzip_types: Vec<String>,
+    // Serve photo.avif or photo.webp for photo.jpg to clients accepting those formats
+    pub image_variants: bool,
+    // Find/replace pairs applied to static files of the rewrite types, {version} is already substituted
+    pub rewrites: Vec<(String, String)>,
+    // Content types of the static files the rewrites apply to
+    pub rewrite_types: Vec<String>,
+    // Headers added to every response, values may contain {request_id} and {remote_addr}
+    pub global_headers: Vec<(String, String)>,
+    // Destinations of the request lines and of the error and diagnostic messages
+    pub access_log: LogTarget,
+    pub error_log: LogTarget,
+    // Output format of the tracing subscriber, no subscriber is installed when None
+    #[cfg(feature = "tracing")]
+    pub trace_format: Option<TraceFormat>,
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut positional = Vec::new();
+        let mut startup_timeout = DEFAULT_STARTUP_TIMEOUT;
+        let mut tcp_nodelay = true;
+        let mut keep_alive_timeout = DEFAULT_KEEP_ALIVE_TIMEOUT;
+        let mut request_timeout = DEFAULT_REQUEST_TIMEOUT;
+        let mut server_header = true;
+        let mut not_found_page = false;
+        let mut max_path_depth = DEFAULT_MAX_PATH_DEPTH;
+        let mut forbidden_paths = Vec::new();
+        let mut require_user_agent = false;
+        let mut user_agent_exempt = Vec::new();
+        let mut required_accept = Vec::new();
+        let mut index_rules = Vec::new();
+        let mut case_sensitive_paths = false;
+        let mut json_listing = false;
+        let mut error_format = ErrorFormat::Empty;
+        let mut scripts_listing = false;
+        let mut script_methods = Vec::new();
+        let mut script_header_allow = Vec::new();
+        let mut script_header_deny: Vec<String> = DEFAULT_SCRIPT_HEADER_DENY.iter().map(|name| name.to_string()).collect();
+        let mut script_content_types = Vec::new();
+        let mut empty_script_status = 204;
+        let mut script_first_byte_timeout = Vec::new();
+        let mut accept_patch = Vec::new();
+        let mut max_script_header_size = DEFAULT_MAX_SCRIPT_HEADER_SIZE;
+        let mut max_script_headers = DEFAULT_MAX_SCRIPT_HEADERS;
+        let mut script_env = Vec::new();
+        let mut decompress_request_bodies = false;
+        let mut max_decompressed_body = DEFAULT_MAX_DECOMPRESSED_BODY;
+        let mut max_decompression_ratio = DEFAULT_MAX_DECOMPRESSION_RATIO;
+        let mut script_nosniff = true;
+        let mut script_stdin_newlines = Vec::new();
+        let mut timing_allow_origins = Vec::new();
+        let mut max_open_files = 0;
+        let mut max_body_size = DEFAULT_MAX_BODY_SIZE;
+        let mut max_send_rate = 0;
+        let mut reset_listener_after = 0;
+        let mut access_log = LogTarget::Stdout;
+        let mut error_log = LogTarget::Stderr;
+        let mut access_log_max_size = 0;
+        let mut error_log_max_size = 0;
+        let mut encoded_roots = Vec::new();
+        let mut gzip = false;
+        let mut gzip_types = Vec::new();
+        let mut image_variants = false;
+        let mut rewrites = Vec::new();
+        let mut rewrite_types = Vec::new();
+        let mut global_headers = Vec::new();
+        #[cfg(feature = "tracing")]
+        let mut trace_format = None;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--startup-timeout" => startup_timeout = Duration::from_secs(parse_number(option_value(&mut args, arg)?, arg)?),
+                "--no-tcp-nodelay" => tcp_nodelay = false,
+                "--keep-alive-timeout" => keep_alive_timeout = Duration::from_secs(parse_number(option_value(&mut args, arg)?, arg)?),
+                "--request-timeout" => request_timeout = Duration::from_secs(parse_number(option_value(&mut args, arg)?, arg)?),
+                "--no-server-header" => server_header = false,
+                "--404-page" => not_found_page = true,
+                "--max-path-depth" => max_path_depth = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--forbid" => forbidden_paths.push(option_value(&mut args, arg)?.to_string()),
+                "--require-user-agent" => require_user_agent = true,
+                "--user-agent-exempt" => user_agent_exempt.push(option_value(&mut args, arg)?.to_string()),
+                "--require-accept" => {
+                    let value = option_value(&mut args, arg)?;
+                    let (prefix, media_type) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("Invalid value for {}: {}, expected PREFIX=TYPE", arg, value))?;
+                    required_accept.push((prefix.to_string(), media_type.to_string()));
+                }
+                "--index-rule" => {
+                    let value = option_value(&mut args, arg)?;
+                    let (media_type, file) = value
+                        .split_once('=')
+                        .filter(|(_, file)| !file.is_empty() && !file.contains('/'))
+                        .ok_or_else(|| format!("Invalid value for {}: {}, expected TYPE=FILE", arg, value))?;
+                    index_rules.push((media_type.to_string(), file.to_string()));
+                }
+                "--case-sensitive-paths" => case_sensitive_paths = true,
+                "--json-listing" => json_listing = true,
+                "--error-format" => {
+                    error_format = match option_value(&mut args, arg)? {
+                        "empty" => ErrorFormat::Empty,
+                        "problem+json" => ErrorFormat::ProblemJson,
+                        other => return Err(format!("Invalid value for {}: {}, expected empty or problem+json", arg, other)),
+                    }
+                }
+                "--scripts-listing" => scripts_listing = true,
+                "--script-method" => {
+                    let method = option_value(&mut args, arg)?;
+                    if !crate::is_token(method) {
+                        return Err(format!("Invalid method name {:?}", method));
+                    }
+                    script_methods.push(method.to_string());
+                }
+                "--script-header-allow" => script_header_allow.push(option_value(&mut args, arg)?.to_string()),
+                "--script-header-deny" => script_header_deny.push(option_value(&mut args, arg)?.to_string()),
+                "--script-content-type" => {
+                    let (prefix, media_type) = parse_prefixed(option_value(&mut args, arg)?, arg, "TYPE")?;
+                    script_content_types.push((prefix, media_type.to_string()));
+                }
+                "--empty-script-status" => {
+                    empty_script_status = match option_value(&mut args, arg)? {
+                        "200" => 200,
+                        "204" => 204,
+                        other => return Err(format!("Invalid value for {}: {}, expected 200 or 204", arg, other)),
+                    }
+                }
+                "--script-first-byte-timeout" => {
+                    let (prefix, seconds) = parse_prefixed(option_value(&mut args, arg)?, arg, "SECONDS")?;
+                    script_first_byte_timeout.push((prefix, Duration::from_secs(parse_number(seconds, arg)?)));
+                }
+                "--accept-patch" => {
+                    let (prefix, media_type) = parse_prefixed(option_value(&mut args, arg)?, arg, "TYPE")?;
+                    accept_patch.push((prefix, media_type.to_string()));
+                }
+                "--script-env" => {
+                    let value = option_value(&mut args, arg)?;
+                    let (prefix, name, variable_value) = value
+                        .split_once(':')
+                        .and_then(|(prefix, variable)| variable.split_once('=').map(|(name, value)| (prefix, name, value)))
+                        .filter(|(prefix, name, _)| prefix.starts_with('/') && !name.is_empty())
+                        .ok_or_else(|| format!("Invalid value for {}: {}, expected PREFIX:NAME=VALUE", arg, value))?;
+                    script_env.push((PathBuf::from(prefix), name.to_string(), variable_value.to_string()));
+                }
+                "--max-script-header-size" => max_script_header_size = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--max-script-headers" => max_script_headers = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--decompress-request-bodies" => decompress_request_bodies = true,
+                "--max-decompressed-body" => max_decompressed_body = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--max-decompression-ratio" => max_decompression_ratio = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--no-script-nosniff" => script_nosniff = false,
+                "--script-stdin-newlines" => {
+                    let (prefix, line_ending) = parse_prefixed(option_value(&mut args, arg)?, arg, "lf|crlf")?;
+                    let line_ending = match line_ending {
+                        "lf" => LineEnding::Lf,
+                        "crlf" => LineEnding::Crlf,
+                        other => return Err(format!("Invalid value for {}: {}, expected lf or crlf", arg, other)),
+                    };
+                    script_stdin_newlines.push((prefix, line_ending));
+                }
+                "--timing-allow-origin" => timing_allow_origins.push(option_value(&mut args, arg)?.to_string()),
+                "--max-open-files" => max_open_files = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--max-body-size" => max_body_size = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--max-send-rate" => max_send_rate = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--access-log" => access_log = LogTarget::parse(option_value(&mut args, arg)?),
+                "--error-log" => error_log = LogTarget::parse(option_value(&mut args, arg)?),
+                "--access-log-max-size" => access_log_max_size = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--error-log-max-size" => error_log_max_size = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--reset-listener-after" => reset_listener_after = parse_number(option_value(&mut args, arg)?, arg)?,
+                "--encoded-root" => {
+                    let value = option_value(&mut args, arg)?;
+                    let (coding, root) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("Invalid value for {}: {}, expected CODING=DIR", arg, value))?;
+                    encoded_roots.push((coding.to_ascii_lowercase(), PathBuf::from(root)));
+                }
+                "--gzip" => gzip = true,
+                "--gzip-type" => gzip_types.push(option_value(&mut args, arg)?.to_string()),
+                "--image-variants" => image_variants = true,
+                "--rewrite" => {
+                    let value = option_value(&mut args, arg)?;
+                    let (find, replace) = value
+                        .split_once('=')
+                        .filter(|(find, _)| !find.is_empty())
+                        .ok_or_else(|| format!("Invalid value for {}: {}, expected FIND=REPLACE", arg, value))?;
+                    rewrites.push((find.to_string(), replace.replace("{version}", env!("CARGO_PKG_VERSION"))));
+                }
+                "--rewrite-type" => rewrite_types.push(option_value(&mut args, arg)?.to_string()),
+                "--header" => global_headers.push(parse_header(option_value(&mut args, arg)?)?),
+                #[cfg(feature = "tracing")]
+                "--trace" => {
+                    trace_format = match option_value(&mut args, arg)? {
+                        "pretty" => Some(TraceFormat::Pretty),
+                        "json" => Some(TraceFormat::Json),
+                        other => return Err(format!("Invalid value for {}: {}, expected pretty or json", arg, other)),
+                    }
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ => positional.push(arg),
+            }
+        }
+
+        if positional.len() != 2 {
+            return Err(USAGE.to_string());
+        }
+        // The sizes can be given before or after the destinations they apply to
+        for (target, size) in [(&mut access_log, access_log_max_size), (&mut error_log, error_log_max_size)] {
+            if let LogTarget::File { max_size, .. } = target {
+                *max_size = size;
+            }
+        }
+        if rewrite_types.is_empty() {
+            rewrite_types = DEFAULT_REWRITE_TYPES.iter().map(|media_type| media_type.to_string()).collect();
+        }
+        if gzip_types.is_empty() {
+            gzip_types = DEFAULT_GZIP_TYPES.iter().map(|prefix| prefix.to_string()).collect();
+        }
+
+        Ok(Config {
+            port: positional[0].clone(),
+            root_folder: PathBuf::from(positional[1]),
+            startup_timeout,
+            tcp_nodelay,
+            keep_alive_timeout,
+            request_timeout,
+            server_header,
+            not_found_page,
+            max_path_depth,
+            forbidden_paths,
+            require_user_agent,
+            user_agent_exempt,
+            required_accept,
+            index_rules,
+            case_sensitive_paths,
+            json_listing,
+            error_format,
+            scripts_listing,
+            script_methods,
+            script_header_allow,
+            script_header_deny,
+            script_content_types,
+            empty_script_status,
+            script_first_byte_timeout,
+            accept_patch,
+            max_script_header_size,
+            max_script_headers,
+            script_env,
+            decompress_request_bodies,
+            max_decompressed_body,
+            max_decompression_ratio,
+            script_nosniff,
+            script_stdin_newlines,
+            timing_allow_origins,
+            max_open_files,
+            max_body_size,
+            max_send_rate,
+            reset_listener_after,
+            encoded_roots,
+            gzip,
+            gzip_types,
+            image_variants,
+            rewrites,
+            rewrite_types,
+            global_headers,
+            access_log,
+            error_log,
+            #[cfg(feature = "tracing")]
+            trace_format,
+        })
+    }
+
+    pub fn scripts_dir(&self) -> PathBuf {
+        self.root_folder.join("scripts")
+    }
+
+    // Matched against the decoded request path with its "." and ".." segments resolved, so "/sub/../private" is "/private"
+    pub fn is_forbidden(&self, path: &str) -> bool {
+        self.forbidden_paths.iter().any(|pattern| matches_path_pattern(pattern, path))
+    }
+
+    pub fn exempts_from_user_agent(&self, path: &str) -> bool {
+        self.user_agent_exempt.iter().any(|prefix| is_under_prefix(path, prefix))
+    }
+
+    // Prefixes match whole segments, "/scripts/db" covers "/scripts/db/query.sh" but not "/scripts/dbx.sh"
+    pub fn script_env_for(&self, script: &Path) -> impl Iterator<Item = (&str, &str)> {
+        let url_path = self.script_url_path(script);
+        self.script_env
+            .iter()
+            .filter(move |(prefix, _, _)| url_path.as_ref().is_some_and(|url_path| url_path.starts_with(prefix)))
+            .map(|(_, name, value)| (name.as_str(), value.as_str()))
+    }
+
+    // The longest prefix matching the script decides, so "/scripts/upload" can have its own value under "/scripts"
+    // Values given for that same prefix are all returned, in order
+    fn for_script<'a, T>(&self, entries: &'a [(PathBuf, T)], script: &Path) -> Vec<&'a T> {
+        let Some(url_path) = self.script_url_path(script) else {
+            return Vec::new();
+        };
+        let covering = || entries.iter().filter(|(prefix, _)| url_path.starts_with(prefix));
+        let Some(longest) = covering().map(|(prefix, _)| prefix.components().count()).max() else {
+            return Vec::new();
+        };
+        covering().filter(|(prefix, _)| prefix.components().count() == longest).map(|(_, value)| value).collect()
+    }
+
+    fn script_url_path(&self, script: &Path) -> Option<PathBuf> {
+        script.strip_prefix(&self.root_folder).ok().map(|relative| Path::new("/").join(relative))
+    }
+
+    // The last value given for the prefix wins
+    pub fn script_first_byte_timeout_for(&self, script: &Path) -> Option<Duration> {
+        self.for_script(&self.script_first_byte_timeout, script).pop().copied()
+    }
+
+    // Every type given for the longest prefix covering the script, none when it has no prefix
+    pub fn accept_patch_for(&self, script: &Path) -> Vec<&str> {
+        self.for_script(&self.accept_patch, script).into_iter().map(String::as_str).collect()
+    }
+
+    pub fn script_stdin_newlines_for(&self, script: &Path) -> Option<LineEnding> {
+        self.for_script(&self.script_stdin_newlines, script).pop().copied()
+    }
+
+    // An allowed header is passed even if it's on the default denylist
+    pub fn passes_header_to_scripts(&self, header: &str) -> bool {
+        if !self.script_header_allow.is_empty() {
+            return self.script_header_allow.iter().any(|name| name.eq_ignore_ascii_case(header));
+        }
+        !self.script_header_deny.iter().any(|name| name.eq_ignore_ascii_case(header))
+    }
+
+    // Parameters like charset are ignored, a missing Content-Type only passes without an allowlist
+    pub fn accepts_script_content_type(&self, script: &Path, content_type: Option<&str>) -> bool {
+        let allowed = self.for_script(&self.script_content_types, script);
+        if allowed.is_empty() {
+            return true;
+        }
+        let Some(content_type) = content_type else {
+            return false;
+        };
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(media_type))
+    }
+
+    // "text/" covers every text type, "application/json" covers only itself
+    pub fn compresses_content_type(&self, content_type: &str) -> bool {
+        let content_type = content_type.to_ascii_lowercase();
+        self.gzip_types.iter().any(|prefix| content_type.starts_with(&prefix.to_ascii_lowercase()))
+    }
+
+    pub fn rewrites_content_type(&self, content_type: &str) -> bool {
+        !self.rewrites.is_empty() && self.rewrite_types.iter().any(|media_type| media_type.eq_ignore_ascii_case(content_type))
+    }
+
+    // Rules apply in the order given, bodies that aren't UTF-8 are left as they are
+    pub fn rewrite_body(&self, body: Vec<u8>) -> Vec<u8> {
+        let mut text = match String::from_utf8(body) {
+            Ok(text) => text,
+            Err(e) => return e.into_bytes(),
+        };
+        for (find, replace) in &self.rewrites {
+            text = text.replace(find.as_str(), replace);
+        }
+        text.into_bytes()
+    }
+
+    // Renders the global headers for one request as header lines
+    pub fn render_global_headers(&self, request_id: u64, remote_addr: &str) -> String {
+        let mut rendered = String::new();
+        for (name, value) in &self.global_headers {
+            let value = value
+                .replace("{request_id}", &request_id.to_string())
+                .replace("{remote_addr}", remote_addr);
+            rendered.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        rendered
+    }
+}
+
+// A pattern without "*" is a prefix: "/private" matches "/private" and everything under it, but not
+// "/privateer". A pattern with "*" has to match the whole path, "*" standing for any characters
+// within a segment and "**" for any characters across segments, so "/**/*.bak" matches backups anywhere
+fn matches_path_pattern(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('*') {
+        return is_under_prefix(path, pattern);
+    }
+    matches_glob(pattern.as_bytes(), path.as_bytes())
+}
+
+// Whole segments only, "/api" covers "/api" and "/api/users" but not "/apix", a trailing slash makes no difference
+pub fn is_under_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn matches_glob(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        // "**/" also matches no directory at all
+        [b'*', b'*', b'/', rest @ ..] => (0..=path.len())
+            .filter(|&skip| skip == 0 || path[skip - 1] == b'/')
+            .any(|skip| matches_glob(rest, &path[skip..])),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|skip| matches_glob(rest, &path[skip..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&skip| skip == 0 || path[skip - 1] != b'/')
+            .any(|skip| matches_glob(rest, &path[skip..])),
+        [byte, rest @ ..] => path.first() == Some(byte) && matches_glob(rest, &path[1..]),
+    }
+}
+
+fn option_value<'a>(args: &mut impl Iterator<Item = &'a String>, option: &str) -> Result<&'a str, String> {
+    args.next()
+        .map(String::as_str)
+        .ok_or_else(|| format!("Missing value for {}", option))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, option: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}, expected a number", option, value))
+}
+
+// "PREFIX=VALUE" applies to the scripts under PREFIX, a bare VALUE to every script
+fn parse_prefixed<'a>(value: &'a str, option: &str, expected: &str) -> Result<(PathBuf, &'a str), String> {
+    if !value.starts_with('/') {
+        return Ok((PathBuf::from("/"), value));
+    }
+    value
+        .split_once('=')
+        .map(|(prefix, value)| (PathBuf::from(prefix), value))
+        .ok_or_else(|| format!("Invalid value for {}: {}, expected [PREFIX=]{}", option, value, expected))
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header {:?}, expected NAME: VALUE", header))?;
+    let (name, value) = (name.trim(), value.trim());
+
+    // Header names are RFC 7230 tokens
+    if !crate::is_token(name) {
+        return Err(format!("Invalid header name {:?}", name));
+    }
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(format!("Invalid value for header {}", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_patterns_match_whole_segments() {
+        assert!(matches_path_pattern("/private", "/private"));
+        assert!(matches_path_pattern("/private", "/private/notes.txt"));
+        assert!(matches_path_pattern("/private/", "/private/notes.txt"));
+        assert!(!matches_path_pattern("/private", "/privateer"));
+        assert!(!matches_path_pattern("/private", "/public/private"));
+    }
+
+    #[test]
+    fn single_star_stays_within_a_segment() {
+        assert!(matches_path_pattern("/*.bak", "/notes.bak"));
+        assert!(!matches_path_pattern("/*.bak", "/dir/notes.bak"));
+        assert!(matches_path_pattern("/logs/*", "/logs/today"));
+        assert!(!matches_path_pattern("/logs/*", "/logs/old/today"));
+    }
+
+    #[test]
+    fn gzip_types_are_content_type_prefixes() {
+        let args: Vec<String> = ["rustywebserver", "8000", "/www", "--gzip", "--gzip-type", "text/html"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::from_args(&args).unwrap();
+        assert!(config.compresses_content_type("text/html"));
+        assert!(!config.compresses_content_type("text/plain"));
+        let args: Vec<String> = ["rustywebserver", "8000", "/www", "--gzip"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::from_args(&args).unwrap();
+        assert!(config.compresses_content_type("text/plain"));
+        assert!(config.compresses_content_type("image/svg+xml"));
+        assert!(!config.compresses_content_type("image/png"));
+    }
+
+    #[test]
+    fn script_settings_use_the_longest_matching_prefix() {
+        let args: Vec<String> = [
+            "rustywebserver", "8000", "/www",
+            "--script-content-type", "text/plain",
+            "--script-content-type", "/scripts/api=application/json",
+            "--script-content-type", "/scripts/api=application/xml",
+            "--script-first-byte-timeout", "/scripts/slow=30",
+            "--script-stdin-newlines", "/scripts/win=crlf",
+            "--accept-patch", "/scripts/api=application/merge-patch+json",
+            "--accept-patch", "/scripts/api=application/json-patch+json",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let config = Config::from_args(&args).unwrap();
+        let api = Path::new("/www/scripts/api/create.sh");
+        assert!(config.accepts_script_content_type(api, Some("application/json; charset=utf-8")));
+        assert!(config.accepts_script_content_type(api, Some("application/xml")));
+        assert!(!config.accepts_script_content_type(api, Some("text/plain")));
+        // "/scripts/apix.sh" isn't under "/scripts/api", so the bare value applies
+        let other = Path::new("/www/scripts/apix.sh");
+        assert!(config.accepts_script_content_type(other, Some("text/plain")));
+        assert!(!config.accepts_script_content_type(other, Some("application/json")));
+        assert!(!config.accepts_script_content_type(other, None));
+        assert_eq!(config.script_first_byte_timeout_for(Path::new("/www/scripts/slow/run.sh")), Some(Duration::from_secs(30)));
+        assert_eq!(config.script_first_byte_timeout_for(other), None);
+        assert!(config.script_stdin_newlines_for(Path::new("/www/scripts/win/run.sh")) == Some(LineEnding::Crlf));
+        assert!(config.script_stdin_newlines_for(other).is_none());
+        assert_eq!(config.accept_patch_for(api), ["application/merge-patch+json", "application/json-patch+json"]);
+        assert!(config.accept_patch_for(other).is_empty());
+    }
+
+    #[test]
+    fn user_agent_exemptions_match_whole_segments() {
+        let args: Vec<String> = ["rustywebserver", "8000", "/www", "--user-agent-exempt", "/health", "--user-agent-exempt", "/public/"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let config = Config::from_args(&args).unwrap();
+        assert!(config.exempts_from_user_agent("/health"));
+        assert!(config.exempts_from_user_agent("/health/live"));
+        assert!(!config.exempts_from_user_agent("/healthz"));
+        assert!(config.exempts_from_user_agent("/public"));
+        assert!(config.exempts_from_user_agent("/public/logo.png"));
+        assert!(!config.exempts_from_user_agent("/publicity.html"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(matches_path_pattern("/**/*.bak", "/notes.bak"));
+        assert!(matches_path_pattern("/**/*.bak", "/a/b/notes.bak"));
+        assert!(!matches_path_pattern("/**/*.bak", "/a/b/notes.bak.txt"));
+        assert!(matches_path_pattern("/a/**", "/a/b/c"));
+    }
+}
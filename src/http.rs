@@ -0,0 +1,302 @@
+use std::fmt;
+
+// A request as read from the connection, before any of it is checked
+pub struct Request {
+    pub method: String,
+    // Still percent-encoded, the query is split off
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    // Header lines as sent, looked up with get_header
+    pub headers: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    // Nothing was sent before the blank line
+    MissingRequestLine,
+    // The request line doesn't have a method, a target and a version
+    MalformedRequestLine,
+}
+
+// Parses a request head, with the body following the blank line that ends it
+pub fn parse_request(raw: &[u8]) -> Result<Request, ParseError> {
+    let (head, body) = match raw.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => (&raw[..end], &raw[end + 4..]),
+        // A truncated head has no body
+        None => (raw, &[][..]),
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+
+    let request_line = lines.next().filter(|line| !line.is_empty()).ok_or(ParseError::MissingRequestLine)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(ParseError::MalformedRequestLine);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        version: version.to_string(),
+        headers: lines.take_while(|line| !line.is_empty()).map(str::to_string).collect(),
+        body: body.to_vec(),
+    })
+}
+
+// A response to send, serialized with the framing its body needs
+pub struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    // Request headers the response was negotiated on, sent as one Vary header
+    vary: Vec<String>,
+    body: Vec<u8>,
+    length: Length,
+    extra_headers: String,
+    head_only: bool,
+}
+
+enum Length {
+    // Content-Length is the length of the body
+    Body,
+    // The body is sent separately after the head, like a streamed file
+    Declared(u64),
+    // The body is sent separately after the head with chunked transfer-encoding
+    Chunked,
+    // No Content-Length, for bodies that are chunked or end when the connection closes, and for 204 and 304
+    Omitted,
+}
+
+impl Response {
+    pub fn status(status: u16, reason: &str) -> Response {
+        // 204 and 304 responses never have a body, so they can't declare a length
+        let length = if matches!(status, 204 | 304) { Length::Omitted } else { Length::Body };
+        Response { status, reason: reason.to_string(), headers: Vec::new(), vary: Vec::new(), body: Vec::new(), length, extra_headers: String::new(), head_only: false }
+    }
+
+    pub fn header(mut self, name: &str, value: impl fmt::Display) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Each negotiation step adds the request header it looked at, a name is only listed once
+    pub fn vary(mut self, header: &str) -> Response {
+        if !self.vary.iter().any(|name| name.eq_ignore_ascii_case(header)) {
+            self.vary.push(header.to_string());
+        }
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        if !matches!(self.length, Length::Omitted) {
+            self.body = body.into();
+            self.length = Length::Body;
+        }
+        self
+    }
+
+    pub fn content_length(mut self, length: u64) -> Response {
+        self.length = Length::Declared(length);
+        self
+    }
+
+    pub fn without_length(mut self) -> Response {
+        self.length = Length::Omitted;
+        self
+    }
+
+    pub fn chunked(mut self) -> Response {
+        self.length = Length::Chunked;
+        self
+    }
+
+    // Without a length or chunked encoding, only closing the connection tells the client where the body ends
+    pub fn ends_at_close(&self) -> bool {
+        matches!(self.length, Length::Omitted) && !matches!(self.status, 204 | 304)
+    }
+
+    // Head lines already formatted by the caller, they go right after the status line
+    pub fn extra_headers(mut self, lines: &str) -> Response {
+        self.extra_headers.push_str(lines);
+        self
+    }
+
+    // For HEAD requests, the length is still declared but the body is left out
+    pub fn head_only(mut self) -> Response {
+        self.head_only = true;
+        self
+    }
+
+    pub fn status_code(&self) -> u16 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    // Whether anything follows the head, either the body set here or one sent separately
+    pub fn has_body(&self) -> bool {
+        match self.length {
+            Length::Body => !self.body.is_empty(),
+            Length::Declared(_) | Length::Chunked => true,
+            Length::Omitted => self.ends_at_close(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n{}", self.status, self.reason, self.extra_headers);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !self.vary.is_empty() {
+            head.push_str(&format!("Vary: {}\r\n", self.vary.join(", ")));
+        }
+        match self.length {
+            Length::Body => head.push_str(&format!("Content-Length: {}\r\n", self.body.len())),
+            Length::Declared(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            Length::Chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+            Length::Omitted => {}
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        if !self.head_only {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_line_headers_and_body() {
+        let request = parse_request(b"POST /scripts/run.sh?x=1&y=2 HTTP/1.1\r\nHost: example\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/scripts/run.sh");
+        assert_eq!(request.query, "x=1&y=2");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.headers, ["Host: example", "Content-Length: 2"]);
+        assert_eq!(request.body, b"hi");
+    }
+
+    #[test]
+    fn keeps_the_path_percent_encoded() {
+        let request = parse_request(b"GET /a%20b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/a%20b");
+        assert_eq!(request.query, "");
+    }
+
+    #[test]
+    fn only_the_first_question_mark_splits_the_query() {
+        let request = parse_request(b"GET /search?q=a?b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.query, "q=a?b");
+    }
+
+    #[test]
+    fn rejects_an_empty_request() {
+        assert!(matches!(parse_request(b""), Err(ParseError::MissingRequestLine)));
+        assert!(matches!(parse_request(b"\r\n\r\n"), Err(ParseError::MissingRequestLine)));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        // A missing method leaves only the target and the version
+        assert!(matches!(parse_request(b"/index.html HTTP/1.1\r\n\r\n"), Err(ParseError::MalformedRequestLine)));
+        assert!(matches!(parse_request(b"GET\r\n\r\n"), Err(ParseError::MalformedRequestLine)));
+        assert!(matches!(parse_request(b"GET /\r\n\r\n"), Err(ParseError::MalformedRequestLine)));
+    }
+
+    #[test]
+    fn truncated_head_has_no_body() {
+        let request = parse_request(b"GET / HTTP/1.1\r\nHost: example").unwrap();
+        assert_eq!(request.headers, ["Host: example"]);
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn headers_end_at_the_blank_line() {
+        let request = parse_request(b"POST / HTTP/1.1\r\nA: 1\r\n\r\nB: 2\r\n").unwrap();
+        assert_eq!(request.headers, ["A: 1"]);
+        assert_eq!(request.body, b"B: 2\r\n");
+    }
+
+    #[test]
+    fn header_values_are_kept_as_sent() {
+        let request = parse_request(b"GET / HTTP/1.1\r\nX-Empty:\r\nX-Colon: a: b\r\n\r\n").unwrap();
+        assert_eq!(request.headers, ["X-Empty:", "X-Colon: a: b"]);
+    }
+
+    #[test]
+    fn response_declares_the_length_of_its_body() {
+        let bytes = Response::status(200, "OK").header("Content-Type", "text/plain").body("hello").into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    #[test]
+    fn response_without_body_has_zero_length() {
+        assert_eq!(Response::status(404, "Not Found").into_bytes(), b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn no_content_and_not_modified_have_no_body_or_length() {
+        let bytes = Response::status(204, "No Content").body("ignored").into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 204 No Content\r\n\r\n");
+        let bytes = Response::status(304, "Not Modified").header("ETag", "\"a\"").body("ignored").into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 304 Not Modified\r\nETag: \"a\"\r\n\r\n");
+    }
+
+    #[test]
+    fn head_only_keeps_the_length_of_the_left_out_body() {
+        let bytes = Response::status(200, "OK").body("hello").head_only().into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+    }
+
+    #[test]
+    fn declared_and_omitted_lengths() {
+        let bytes = Response::status(200, "OK").content_length(1234).into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+        let bytes = Response::status(200, "OK").header("Transfer-Encoding", "chunked").without_length().into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+    }
+
+    #[test]
+    fn chunked_bodies_and_bodies_ending_at_close() {
+        let response = Response::status(200, "OK").chunked();
+        assert!(!response.ends_at_close());
+        assert_eq!(response.into_bytes(), b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(Response::status(200, "OK").without_length().ends_at_close());
+        assert!(!Response::status(200, "OK").content_length(3).ends_at_close());
+        // Not Modified has no body to end
+        assert!(!Response::status(304, "Not Modified").ends_at_close());
+    }
+
+    #[test]
+    fn has_body_counts_bodies_sent_after_the_head() {
+        assert!(!Response::status(404, "Not Found").has_body());
+        assert!(Response::status(404, "Not Found").body("missing").has_body());
+        assert!(Response::status(200, "OK").content_length(10).has_body());
+        assert!(Response::status(200, "OK").chunked().has_body());
+        assert!(!Response::status(304, "Not Modified").has_body());
+    }
+
+    #[test]
+    fn vary_lists_each_negotiated_header_once() {
+        let bytes = Response::status(200, "OK").vary("Accept-Encoding").vary("Accept").vary("accept").into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nVary: Accept-Encoding, Accept\r\nContent-Length: 0\r\n\r\n");
+        assert!(!String::from_utf8(Response::status(200, "OK").into_bytes()).unwrap().contains("Vary"));
+    }
+
+    #[test]
+    fn extra_headers_follow_the_status_line() {
+        let bytes = Response::status(200, "OK").header("ETag", "\"a\"").extra_headers("Connection: close\r\n").into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nConnection: close\r\nETag: \"a\"\r\nContent-Length: 0\r\n\r\n");
+    }
+}
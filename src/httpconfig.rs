@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+const FILE_NAME: &str = ".httpconfig";
+
+// Settings from the .httpconfig files of a directory and its parents
+#[derive(Default)]
+pub struct DirectoryConfig {
+    // Whether the directory may be listed, inherited from the parent when not set
+    pub listing: Option<bool>,
+    // Extra response headers, a deeper file replaces a header with the same name
+    pub headers: Vec<(String, String)>,
+}
+
+// Parsed files and the modification time they were parsed at
+type Cache = Mutex<HashMap<PathBuf, (SystemTime, Arc<DirectoryConfig>)>>;
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+pub fn is_config_file(path: &Path) -> bool {
+    path.file_name() == Some(FILE_NAME.as_ref())
+}
+
+// Merges the files from the root folder down to the directory of the requested path
+pub fn resolve(root_folder: &Path, full_path: &Path) -> DirectoryConfig {
+    let directory = if full_path.is_dir() {
+        full_path
+    } else {
+        full_path.parent().unwrap_or(root_folder)
+    };
+    let mut merged = DirectoryConfig::default();
+    let Ok(relative) = directory.strip_prefix(root_folder) else {
+        return merged;
+    };
+
+    let mut current = root_folder.to_path_buf();
+    merge(&mut merged, &current);
+    // Only plain names are followed, so ".." can't pull in files from outside the root folder
+    for component in relative.components() {
+        if let Component::Normal(name) = component {
+            current.push(name);
+            merge(&mut merged, &current);
+        }
+    }
+    merged
+}
+
+fn merge(merged: &mut DirectoryConfig, directory: &Path) {
+    let Some(config) = load(directory) else {
+        return;
+    };
+    if config.listing.is_some() {
+        merged.listing = config.listing;
+    }
+    for (name, value) in &config.headers {
+        merged.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        merged.headers.push((name.clone(), value.clone()));
+    }
+}
+
+fn load(directory: &Path) -> Option<Arc<DirectoryConfig>> {
+    let path = directory.join(FILE_NAME);
+    let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((parsed_at, config)) = cache.lock().unwrap().get(&path) {
+        if *parsed_at == modified {
+            return Some(Arc::clone(config));
+        }
+    }
+
+    // An invalid file is cached as empty, so the warning is only logged once per change
+    let config = match fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|contents| parse(&contents)) {
+        Ok(config) => config,
+        Err(e) => {
+            error_log!("Ignoring {}: {}", path.display(), e);
+            DirectoryConfig::default()
+        }
+    };
+    let config = Arc::new(config);
+    cache.lock().unwrap().insert(path, (modified, Arc::clone(&config)));
+    Some(config)
+}
+
+fn parse(contents: &str) -> Result<DirectoryConfig, String> {
+    let table: toml::Table = contents.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+    let mut config = DirectoryConfig::default();
+    for (key, value) in table {
+        match key.as_str() {
+            "listing" => config.listing = Some(value.as_bool().ok_or("listing must be a boolean")?),
+            "headers" => {
+                let headers = value.as_table().ok_or("headers must be a table")?;
+                for (name, value) in headers {
+                    let value = value.as_str().ok_or_else(|| format!("header {} must be a string", name))?;
+                    if !crate::is_token(name) || value.chars().any(|c| c.is_control() && c != '\t') {
+                        return Err(format!("invalid header {}", name));
+                    }
+                    config.headers.push((name.clone(), value.to_string()));
+                }
+            }
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("rustywebserver-{}-httpconfig-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn parses_listing_and_headers() {
+        let config = parse("listing = false\n[headers]\nCache-Control = \"no-store\"\n").unwrap();
+        assert_eq!(config.listing, Some(false));
+        assert_eq!(config.headers, [("Cache-Control".to_string(), "no-store".to_string())]);
+        assert!(parse("").unwrap().listing.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(parse("listing = ").is_err());
+        assert_eq!(parse("listing = \"no\"").err().unwrap(), "listing must be a boolean");
+        assert_eq!(parse("headers = \"X-A: b\"").err().unwrap(), "headers must be a table");
+        assert_eq!(parse("index = \"home.html\"").err().unwrap(), "unknown setting index");
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert_eq!(parse("[headers]\nX-Count = 3\n").err().unwrap(), "header X-Count must be a string");
+        // Names are tokens and values can't break the header line
+        assert_eq!(parse("[headers]\n\"X Space\" = \"a\"\n").err().unwrap(), "invalid header X Space");
+        assert_eq!(parse("[headers]\nX-Injected = \"a\\r\\nSet-Cookie: b\"\n").err().unwrap(), "invalid header X-Injected");
+        assert!(parse("[headers]\nX-Tabbed = \"a\\tb\"\n").is_ok());
+    }
+
+    #[test]
+    fn deeper_files_override_shallower_ones() {
+        let root = temp_root("override");
+        fs::create_dir_all(root.join("docs/api")).unwrap();
+        fs::write(root.join(FILE_NAME), "[headers]\nCache-Control = \"max-age=60\"\nX-Team = \"web\"\n").unwrap();
+        fs::write(root.join("docs/api").join(FILE_NAME), "[headers]\ncache-control = \"no-store\"\n").unwrap();
+
+        let config = resolve(&root, &root.join("docs/api/index.html"));
+        assert_eq!(config.headers, [("X-Team".to_string(), "web".to_string()), ("cache-control".to_string(), "no-store".to_string())]);
+        // Directories above the deeper file only see the root's settings
+        let config = resolve(&root, &root.join("docs/guide.html"));
+        assert_eq!(config.headers.len(), 2);
+        assert!(config.headers.contains(&("Cache-Control".to_string(), "max-age=60".to_string())));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_subdirectory_can_disable_listing() {
+        let root = temp_root("listing");
+        fs::create_dir_all(root.join("public/private/deeper")).unwrap();
+        fs::write(root.join(FILE_NAME), "listing = true\n").unwrap();
+        fs::write(root.join("public/private").join(FILE_NAME), "listing = false\n").unwrap();
+
+        assert_eq!(resolve(&root, &root.join("public")).listing, Some(true));
+        assert_eq!(resolve(&root, &root.join("public/private")).listing, Some(false));
+        // Inherited by the directories below it
+        assert_eq!(resolve(&root, &root.join("public/private/deeper")).listing, Some(false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn invalid_files_are_ignored() {
+        let root = temp_root("invalid");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join(FILE_NAME), "listing = false\n").unwrap();
+        fs::write(root.join("sub").join(FILE_NAME), "listing = maybe\n").unwrap();
+        assert_eq!(resolve(&root, &root.join("sub")).listing, Some(false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+// Where the access or error log is written
+pub enum LogTarget {
+    Stdout,
+    Stderr,
+    // Appended to, and moved to PATH.1 once it grows past max_size bytes when that isn't 0
+    File { path: PathBuf, max_size: u64 },
+}
+
+impl LogTarget {
+    pub fn parse(value: &str) -> LogTarget {
+        match value {
+            "stdout" | "-" => LogTarget::Stdout,
+            "stderr" => LogTarget::Stderr,
+            path => LogTarget::File { path: PathBuf::from(path), max_size: 0 },
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+enum Sink {
+    Stdout,
+    Stderr,
+    File(Mutex<LogFile>),
+}
+
+// Until init is called, access lines go to stdout and errors to stderr
+static ACCESS_LOG: OnceLock<Sink> = OnceLock::new();
+static ERROR_LOG: OnceLock<Sink> = OnceLock::new();
+
+pub fn init(access: &LogTarget, error: &LogTarget) -> io::Result<()> {
+    let _ = ACCESS_LOG.set(open_sink(access)?);
+    let _ = ERROR_LOG.set(open_sink(error)?);
+    Ok(())
+}
+
+fn open_sink(target: &LogTarget) -> io::Result<Sink> {
+    Ok(match target {
+        LogTarget::Stdout => Sink::Stdout,
+        LogTarget::Stderr => Sink::Stderr,
+        LogTarget::File { path, max_size } => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let size = file.metadata()?.len();
+            Sink::File(Mutex::new(LogFile { path: path.clone(), max_size: *max_size, file, size }))
+        }
+    })
+}
+
+pub fn access(line: fmt::Arguments) {
+    write_line(ACCESS_LOG.get().unwrap_or(&Sink::Stdout), line);
+}
+
+pub fn error(line: fmt::Arguments) {
+    write_line(ERROR_LOG.get().unwrap_or(&Sink::Stderr), line);
+}
+
+fn write_line(sink: &Sink, line: fmt::Arguments) {
+    match sink {
+        Sink::Stdout => println!("{}", line),
+        Sink::Stderr => eprintln!("{}", line),
+        Sink::File(log_file) => {
+            // A failing log file must not take requests down with it, so write errors are dropped
+            let mut log_file = log_file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let line = format!("{}\n", line);
+            if log_file.max_size > 0 && log_file.size > 0 && log_file.size + line.len() as u64 > log_file.max_size {
+                let _ = log_file.rotate();
+            }
+            if log_file.file.write_all(line.as_bytes()).is_ok() {
+                log_file.size += line.len() as u64;
+            }
+        }
+    }
+}
+
+impl LogFile {
+    fn rotate(&mut self) -> io::Result<()> {
+        // Only one old file is kept, the previous PATH.1 is replaced
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// Like println!, but to the access log
+macro_rules! access_log {
+    ($($arg:tt)*) => {
+        $crate::logging::access(format_args!($($arg)*))
+    };
+}
+
+// Like eprintln!, but to the error log
+macro_rules! error_log {
+    ($($arg:tt)*) => {
+        $crate::logging::error(format_args!($($arg)*))
+    };
+}
@@ -1,194 +1,2560 @@
+#[macro_use]
+mod logging;
+mod config;
+mod http;
+mod httpconfig;
+
+use config::{Config, ErrorFormat, LineEnding};
+use http::{Request, Response};
+use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::mem;
 use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+// Delay after a failed accept, doubled on each consecutive failure up to the maximum
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Served for a directory instead of its listing when present
+const INDEX_FILE: &str = "index.html";
+
+// Requests whose headers don't end within this many bytes get 400
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+// Static files are sent in chunks of this size, so memory doesn't grow with the file
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Images that --image-variants can replace, and the formats tried instead in order of preference
+const IMAGE_VARIANT_SOURCES: &[&str] = &["image/jpeg", "image/png"];
+const IMAGE_VARIANTS: &[(&str, &str)] = &[("image/avif", "avif"), ("image/webp", "webp")];
+
+// The largest file --gzip compresses in memory, larger ones are compressed while they're sent
+const MAX_GZIP_SIZE: u64 = 1024 * 1024;
+
+// Larger files are served without applying the --rewrite rules, which need the whole file in memory
+const MAX_REWRITE_SIZE: u64 = 1024 * 1024;
 
-fn get_mime_type(_: &Path ) -> Option<&'static str> {
-    None
+// Script headers that would contradict how the server frames the response, so they are dropped:
+// the length is computed from the body that is actually sent, and the connection is the server's to manage
+const SERVER_OWNED_HEADERS: &[&str] = &["Content-Length", "Transfer-Encoding", "Connection", "Keep-Alive", "Date", "Server"];
+
+// How long a request waits for a free slot when --max-open-files is reached
+const FILE_SLOT_WAIT: Duration = Duration::from_millis(100);
+
+// Number of files and directories currently open for static serving
+static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Sequence number used to identify requests in templated headers
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// Content-Type of static files by extension, files with other extensions are sent without one
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("wasm", "application/wasm"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+];
+
+fn get_mime_type(path: &Path) -> Option<&'static str> {
+    // Extensions match regardless of case, so photo.JPG is still image/jpeg
+    let extension = path.extension()?.to_str()?;
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map(|(_, mime_type)| *mime_type)
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     // Parse command-line arguments
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: rustwebserver PORT ROOT_FOLDER");
+    let mut config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = logging::init(&config.access_log, &config.error_log) {
+        eprintln!("Failed to open the log files: {}", e);
         return;
     }
-    let port = &args[1];
-    let root_folder = PathBuf::from(&args[2]);
 
-    // Log root folder and server listening address
-    println!("Root folder: {:?}", fs::canonicalize(&root_folder).unwrap());
-    println!("Server listening on 0.0.0.0:{}", port);
+    #[cfg(feature = "tracing")]
+    init_tracing(config.trace_format);
 
-    // Set up TCP listener
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-    let root_folder = Arc::new(root_folder);
+    // Resolve the root folder and set up the TCP listener, giving up if either hangs
+    let root_folder = run_startup_step("resolve the root folder", config.startup_timeout, tokio::fs::canonicalize(&config.root_folder)).await;
+    let address = format!("0.0.0.0:{}", config.port);
+    let mut listener = run_startup_step("bind the listener", config.startup_timeout, TcpListener::bind(&address)).await;
+
+    // Log root folder and server listening address
+    println!("Root folder: {:?}", root_folder);
+    println!("Server listening on 0.0.0.0:{}", config.port);
+    // Requests are resolved against the canonical root, which containment checks compare with
+    config.root_folder = root_folder;
+    let config = Arc::new(config);
 
     // Handle incoming connections
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let root_folder = Arc::clone(&root_folder);
-                thread::spawn(move || {
-                    handle_client(stream, &root_folder);
-                });
+    let mut accept_backoff = ACCEPT_BACKOFF_MIN;
+    let mut next_reset = config.reset_listener_after;
+    loop {
+        match listener.accept().await {
+            Ok((stream, client_addr)) => {
+                accept_backoff = ACCEPT_BACKOFF_MIN;
+                spawn_client(stream, client_addr.ip(), &config);
             }
-            Err(e) => eprintln!("Connection failed: {}", e),
+            Err(e) => {
+                // Errors like EMFILE repeat immediately, so wait before accepting again
+                error_log!("Connection failed: {}", e);
+                tokio::time::sleep(accept_backoff).await;
+                accept_backoff = (accept_backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+
+        let served = NEXT_REQUEST_ID.load(Ordering::Relaxed) - 1;
+        if config.reset_listener_after > 0 && served >= next_reset {
+            error_log!("Reopening the listener after {} requests", served);
+            listener = reopen_listener(listener, &address, &config).await;
+            next_reset = served + config.reset_listener_after;
         }
     }
 }
 
-fn handle_client(mut stream: TcpStream, root_folder: &Path) {
-    let client_addr = stream.peer_addr().unwrap().ip(); // Get client IP address
-    let mut buffer = [0; 8192];
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            if size == 0 {
-                return;
-            }
-            let request = String::from_utf8_lossy(&buffer[..size]);
-            let mut lines = request.lines();
-            if let Some(request_line) = lines.next() {
-                let mut parts = request_line.split_whitespace();
-                if let (Some(method), Some(path), Some(_)) = (parts.next(), parts.next(), parts.next()) {
-                    // Parse headers
-                    let mut headers = Vec::new();
-                    for line in lines {
-                        if line.is_empty() {
-                            break;
-                        }
-                        headers.push(line.to_string());
-                    }
-
-                    // Determine the full path
-                    let full_path = root_folder.join(&path[1..]);
-                    let response = match method {
-                        "GET" => handle_get_request(&full_path, &headers, client_addr),
-                        "POST" => handle_post_request(&full_path, &headers, &buffer[size..]),
-                        _ => http_response(405, "Method Not Allowed", None, None),
-                    };
-
-                    // Send response
-                    let _ = stream.write_all(response.as_bytes());
-                    stream.flush().unwrap();
-
-                    // Log request with client IP address and requested file path
-                    let status_code = response.split_whitespace().nth(1).unwrap();
-                    println!("{} {} {} -> {} ({})", method, client_addr, path, status_code, get_status_text(status_code));
-
-                } else {
-                    let response = http_response(400, "Bad Request", None, None);
-                    let _ = stream.write_all(response.as_bytes());
+fn spawn_client(stream: TcpStream, client_addr: IpAddr, config: &Arc<Config>) {
+    let config = Arc::clone(config);
+    tokio::spawn(async move {
+        handle_client(stream, client_addr, &config).await;
+    });
+}
+
+async fn reopen_listener(listener: TcpListener, address: &str, config: &Arc<Config>) -> TcpListener {
+    // Connections already waiting in the backlog are accepted first, so closing the listener doesn't reset them
+    while let Ok(Ok((stream, client_addr))) = tokio::time::timeout(Duration::ZERO, listener.accept()).await {
+        spawn_client(stream, client_addr.ip(), config);
+    }
+    drop(listener);
+    loop {
+        match TcpListener::bind(address).await {
+            Ok(listener) => return listener,
+            Err(e) => {
+                error_log!("Failed to reopen the listener: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF_MAX).await;
+            }
+        }
+    }
+}
+
+async fn run_startup_step<T>(step: &str, timeout: Duration, run: impl Future<Output = io::Result<T>>) -> T {
+    // tokio runs filesystem calls on a blocking thread, so a stuck one can't hang startup past the timeout
+    match tokio::time::timeout(timeout, run).await {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            eprintln!("Failed to {}: {}", step, e);
+            process::exit(1);
+        }
+        Err(_) => {
+            eprintln!("Timed out after {}s trying to {}", timeout.as_secs_f64(), step);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn init_tracing(format: Option<config::TraceFormat>) {
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::EnvFilter;
+
+    let Some(format) = format else {
+        return;
+    };
+    // Spans are reported when they close, with their duration, filtered by RUST_LOG (info by default)
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_span_events(FmtSpan::CLOSE);
+    match format {
+        config::TraceFormat::Pretty => subscriber.pretty().init(),
+        config::TraceFormat::Json => subscriber.json().init(),
+    }
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "connection", skip_all, fields(client = %client_addr)))]
+async fn handle_client(mut stream: TcpStream, client_addr: IpAddr, config: &Config) {
+    // Responses are written in few large writes, so Nagle's algorithm only delays small ones
+    if let Err(e) = stream.set_nodelay(config.tcp_nodelay) {
+        error_log!("Failed to set TCP_NODELAY: {}", e);
+    }
+
+    // Requests are served until one of them closes the connection, or none arrives within the idle timeout
+    // Limits on the connection as a whole are decided here, the response they end with carries Connection: close
+    let may_keep_alive = !config.keep_alive_timeout.is_zero();
+    let mut pending = Vec::new();
+    let mut kept_alive = false;
+    loop {
+        // Only the wait for the next request is bounded by the keep-alive timeout, once its first byte
+        // arrived it has the same request timeout as the first one
+        if kept_alive && pending.is_empty() {
+            let mut chunk = [0; 8192];
+            match tokio::time::timeout(config.keep_alive_timeout, stream.read(&mut chunk)).await {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(size)) => pending.extend_from_slice(&chunk[..size]),
+                Ok(Err(e)) => {
+                    error_log!("Failed to read from connection: {}", e);
+                    break;
+                }
+            }
+        }
+        let read = read_request(&mut stream, &mut pending, config.max_body_size);
+        let result = match Some(config.request_timeout).filter(|timeout| !timeout.is_zero()) {
+            None => read.await,
+            Some(timeout) => match tokio::time::timeout(timeout, read).await {
+                Ok(result) => result,
+                Err(_) => {
+                    // A client that started a request but never finished it would hold the task forever
+                    let response = with_error_body(config, Response::status(408, "Request Timeout"), None);
+                    let _ = send_response(&mut stream, response, &(standard_headers(config) + connection_header(false)), true).await;
+                    break;
+                }
+            },
+        };
+        match result {
+            Ok(None) => break,
+            Ok(Some(raw)) => {
+                if !handle_request(&mut stream, client_addr, &raw, may_keep_alive, config).await {
+                    break;
                 }
             }
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                // The rest of the body is never read, so the connection can't be used for another request
+                let response = with_error_body(config, Response::status(413, "Payload Too Large"), None);
+                let _ = send_response(&mut stream, response, &(standard_headers(config) + connection_header(false)), true).await;
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // Headers that never end within the limit, or a body whose length can't be told
+                let response = with_error_body(config, Response::status(400, "Bad Request"), None);
+                let _ = send_response(&mut stream, response, &(standard_headers(config) + connection_header(false)), true).await;
+                break;
+            }
+            Err(e) => {
+                error_log!("Failed to read from connection: {}", e);
+                break;
+            }
+        }
+        kept_alive = true;
+    }
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "request", skip_all, fields(method, path, status)))]
+// Returns whether the connection stays open for another request, never when may_keep_alive is false
+// Whatever is returned has been announced in the response's Connection header
+async fn handle_request(stream: &mut TcpStream, client_addr: IpAddr, raw: &[u8], may_keep_alive: bool, config: &Config) -> bool {
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    // Every response carries the Date HTTP/1.1 requires and the Server, ahead of the configured headers
+    let mut extra_headers = standard_headers(config);
+    extra_headers.push_str(&config.render_global_headers(request_id, &client_addr.to_string()));
+    let Ok(Request { method, path: raw_path, query, version, headers, body }) = http::parse_request(raw) else {
+        let response = with_error_body(config, Response::status(400, "Bad Request"), None);
+        extra_headers.push_str(connection_header(false));
+        let _ = send_response(stream, response, &extra_headers, true).await;
+        return false;
+    };
+    let (method, raw_path, query, version, body) = (method.as_str(), raw_path.as_str(), query.as_str(), version.as_str(), body.as_slice());
+    // Files are looked up by the decoded path, a malformed one is answered with 400 below
+    let decoded_path = percent_decode(raw_path);
+    let path = decoded_path.as_deref().unwrap_or(raw_path);
+    // "." and ".." segments are resolved once, the path patterns and everything looked up on disk go through the result
+    let normalized_path = decoded_path.as_deref().and_then(normalize_path);
+    let file_path = normalized_path.as_deref().unwrap_or("/");
+    let relative_path = file_path.strip_prefix('/').unwrap_or_default();
+
+    let mut keep_alive = may_keep_alive && wants_keep_alive(version, &headers);
+
+    #[cfg(feature = "tracing")]
+    let request_span = tracing::Span::current();
+    #[cfg(feature = "tracing")]
+    request_span.record("method", method).record("path", path);
+
+    // HEAD is answered exactly like GET, only the body is left out when sending
+    let request_method = method;
+    let send_body = method != "HEAD";
+    let method = if send_body { method } else { "GET" };
+
+    // Static files expose their detailed resource timing to the configured origins
+    if method == "GET" && !config.timing_allow_origins.is_empty() {
+        extra_headers.push_str(&format!("Timing-Allow-Origin: {}\r\n", config.timing_allow_origins.join(", ")));
+    }
+
+    // Determine the full path
+    let mut full_path = config.root_folder.join(relative_path);
+    if full_path.starts_with(config.scripts_dir()) {
+        // A trailing slash doesn't turn a script into a directory, "/scripts/run.sh/" still runs it
+        full_path = full_path.components().collect();
+    }
+
+    // Settings from the .httpconfig files on the way to the requested directory
+    let directory_config = httpconfig::resolve(&config.root_folder, &full_path);
+    for (name, value) in &directory_config.headers {
+        extra_headers.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    let mut file_body = None;
+    let response = if !is_token(method) {
+        // Methods are RFC 7230 tokens, anything else is a malformed request line
+        Response::status(400, "Bad Request")
+    } else if normalized_path.is_none() {
+        // Malformed escapes, targets not starting with "/" like "*", or ".." segments climbing above the root folder
+        Response::status(400, "Bad Request")
+    } else if !is_safe_path(path) || path_depth(path) > config.max_path_depth {
+        // Reject targets that could point outside the root folder, or are nested too deep
+        Response::status(400, "Bad Request")
+    } else if !is_inside_root(&config.root_folder, &full_path) {
+        // Symlinks must not lead outside the root folder
+        Response::status(403, "Forbidden")
+    } else if config.is_forbidden(file_path) {
+        Response::status(403, "Forbidden")
+    } else if config.require_user_agent && get_header(&headers, "User-Agent").is_none() && !config.exempts_from_user_agent(file_path) {
+        // Clients that don't identify themselves are mostly scripted
+        Response::status(400, "Bad Request")
+    } else if get_header(&headers, "Transfer-Encoding").is_some_and(|codings| !is_supported_transfer_encoding(codings)) {
+        // identity is the same as no transfer-encoding, and chunked bodies were already decoded by read_request
+        // Where the unsupported body ends is unknown, so nothing after it can be read
+        keep_alive = false;
+        Response::status(501, "Not Implemented")
+    } else if !meets_required_accept(config, file_path, &headers) {
+        // These paths only serve a media type the client has to accept
+        Response::status(406, "Not Acceptable")
+    } else if config.case_sensitive_paths && full_path.exists() && !matches_case_on_disk(&config.root_folder, relative_path) {
+        // Case-insensitive filesystems would otherwise find the file under any casing
+        match method {
+            "GET" => not_found_response(config).await,
+            _ => Response::status(404, "Not Found"),
+        }
+    } else if full_path.starts_with(config.scripts_dir()) && full_path.is_dir() && !(method == "GET" && config.scripts_listing) {
+        // The scripts directory itself can't be executed, it's only listed when enabled
+        Response::status(403, "Forbidden")
+    } else if method == "GET"
+        && full_path.exists()
+        && get_header(&headers, "Accept-Encoding").is_some_and(|accept_encoding| {
+            identity_refused(accept_encoding) && encoded_variant(config, &full_path, accept_encoding).is_none() && !gzips(config, &full_path, accept_encoding)
+        })
+    {
+        // Files are only available uncompressed, which the client refuses, unless an encoded root has a copy or --gzip compresses them
+        Response::status(406, "Not Acceptable")
+    } else if httpconfig::is_config_file(&full_path) {
+        // The settings files themselves are never served
+        match method {
+            "GET" => not_found_response(config).await,
+            _ => Response::status(404, "Not Found"),
+        }
+    } else if method == "GET" && full_path.is_dir() && !file_path.ends_with('/') {
+        // Links in listings and index pages are relative, so they need the directory's trailing slash
+        let location = if query.is_empty() { format!("{}/", raw_path) } else { format!("{}/?{}", raw_path, query) };
+        Response::status(301, "Moved Permanently").header("Location", location)
+    } else if let Some(index_page) = index_page(method, &full_path, &headers, config) {
+        // A directory with an index page is served as that page, even when listings are disabled
+        let negotiated = !config.index_rules.is_empty();
+        let response = handle_get_request(&index_page, version, &headers, config, &mut file_body, negotiated).await;
+        if !negotiated {
+            response
+        } else {
+            response.vary("Accept")
+        }
+    } else if method == "GET" && full_path.is_dir() && directory_config.listing == Some(false) {
+        Response::status(403, "Forbidden")
+    } else if method == "GET" && full_path.is_dir() {
+        // Directory listings are streamed to the client while the directory is read
+        let (status_code, keep_alive) = handle_directory_request(stream, &full_path, file_path, query, &headers, version, &extra_headers, keep_alive, send_body, config).await;
+        #[cfg(feature = "tracing")]
+        request_span.record("status", status_code);
+        access_log!("{} {} {} -> {} ({})", request_method, client_addr, path, status_code, get_status_text(status_code));
+        return keep_alive;
+    } else {
+        match method {
+            "GET" => handle_get_request(&full_path, version, &headers, config, &mut file_body, false).await,
+            // Scripts are waited for synchronously, so the worker thread is handed over to other connections meanwhile
+            "POST" => tokio::task::block_in_place(|| handle_post_request(method, &full_path, &headers, body, config)),
+            // PATCH is only meaningful for scripts, static files keep answering 405
+            "PATCH" if full_path.starts_with(config.scripts_dir()) => {
+                tokio::task::block_in_place(|| handle_post_request(method, &full_path, &headers, body, config))
+            }
+            // Extension methods are passed to scripts as an opaque REQUEST_METHOD
+            _ if config.script_methods.iter().any(|allowed| allowed == method) && full_path.starts_with(config.scripts_dir()) => {
+                tokio::task::block_in_place(|| handle_post_request(method, &full_path, &headers, body, config))
+            }
+            "OPTIONS" if !full_path.exists() => Response::status(404, "Not Found"),
+            "OPTIONS" => options_response(config, &full_path),
+            _ => Response::status(405, "Method Not Allowed").header("Allow", allowed_methods(config, &full_path)),
+        }
+    };
+
+    // Send response, a file body follows the head
+    let response = with_error_body(config, response, Some(raw_path));
+    let status_code = response.status_code().to_string();
+    if response.ends_at_close() {
+        keep_alive = false;
+    }
+    extra_headers.push_str(connection_header(keep_alive));
+    let mut sent = send_response(stream, response, &extra_headers, send_body).await;
+    if let (Ok(()), Some(file_body), true) = (&sent, file_body, send_body) {
+        sent = send_file_body(stream, file_body, config.max_send_rate).await;
+    }
+    if sent.is_ok() {
+        sent = stream.flush().await;
+    }
+    // A response cut off halfway leaves the client unable to find where the next one starts
+    if let Err(e) = sent {
+        error_log!("Failed to send response: {}", e);
+        keep_alive = false;
+    }
+
+    // Log request with client IP address and requested file path
+    #[cfg(feature = "tracing")]
+    request_span.record("status", &status_code);
+    access_log!("{} {} {} -> {} ({})", request_method, client_addr, path, status_code, get_status_text(&status_code));
+    keep_alive
+}
+
+fn wants_keep_alive(version: &str, headers: &[String]) -> bool {
+    // HTTP/1.1 connections are persistent unless the client closes them, HTTP/1.0 ones only on request
+    let connection = get_header(headers, "Connection").unwrap_or_default();
+    let has_option = |option: &str| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case(option));
+    match version {
+        "HTTP/1.1" => !has_option("close"),
+        _ => has_option("keep-alive"),
+    }
+}
+
+fn connection_header(keep_alive: bool) -> &'static str {
+    if keep_alive {
+        "Connection: keep-alive\r\n"
+    } else {
+        "Connection: close\r\n"
+    }
+}
+
+// Reads the request head up to the blank line and the body declared by Content-Length or sent
+// chunked, None when the client closed the connection without sending anything. Bytes read past
+// the body are left in pending for the next request
+// A body over max_body_size, when that isn't 0, is an io::ErrorKind::FileTooLarge error
+async fn read_request(stream: &mut TcpStream, pending: &mut Vec<u8>, max_body_size: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut request = mem::take(pending);
+    let mut chunk = [0; 8192];
+    let head_end = loop {
+        let terminator = find_bytes(&request, b"\r\n\r\n");
+        if terminator.unwrap_or(request.len()) > MAX_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request headers too large"));
+        }
+        if let Some(position) = terminator {
+            break position + 4;
+        }
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            if request.is_empty() {
+                return Ok(None);
+            }
+            // A truncated head is still parsed, whatever is missing gets rejected later
+            break request.len();
+        }
+        request.extend_from_slice(&chunk[..size]);
+    };
+
+    let mut body = request.split_off(head_end);
+    if is_chunked(&request) {
+        // Transfer-Encoding overrides Content-Length, the decoded body is then described as if it had been sent with one
+        let body = read_chunked_body(stream, body, pending, max_body_size).await?;
+        let mut request = reframe_head(&request, body.len());
+        request.extend_from_slice(&body);
+        return Ok(Some(request));
+    }
+    let content_length = declared_content_length(&request)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length"))?;
+    // Checked before anything is read, the declared length could be anything up to u64::MAX
+    if max_body_size > 0 && content_length > max_body_size {
+        return Err(body_too_large());
+    }
+    if body.len() as u64 > content_length {
+        *pending = body.split_off(content_length as usize);
+    }
+    let missing = content_length - body.len() as u64;
+    stream.take(missing).read_to_end(&mut body).await?;
+    request.append(&mut body);
+    Ok(Some(request))
+}
+fn is_chunked(head: &[u8]) -> bool {
+    // chunked has to be the last coding, otherwise the body isn't framed by it
+    String::from_utf8_lossy(head)
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .rfind(|(name, _)| name.trim().eq_ignore_ascii_case("Transfer-Encoding"))
+        .is_some_and(|(_, codings)| codings.rsplit(',').next().is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked")))
+}
+
+fn body_too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, "request body too large")
+}
+
+async fn read_chunked_body(stream: &mut TcpStream, mut buffered: Vec<u8>, pending: &mut Vec<u8>, max_body_size: u64) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid chunked body");
+    let mut body = Vec::new();
+    let mut position = 0;
+    loop {
+        // Each chunk is its size in hex, optional extensions, then the data and a CRLF
+        let line = read_chunk_line(stream, &mut buffered, &mut position).await?;
+        let size = line.split(|&byte| byte == b';').next().unwrap_or_default().trim_ascii();
+        if size.is_empty() || size.len() > 15 || !size.iter().all(u8::is_ascii_hexdigit) {
+            return Err(invalid());
+        }
+        let size = usize::from_str_radix(std::str::from_utf8(size).map_err(|_| invalid())?, 16).map_err(|_| invalid())?;
+        if size == 0 {
+            break;
+        }
+        // Checked before the chunk is buffered, so the limit bounds what's held in memory
+        if max_body_size > 0 && (body.len() + size) as u64 > max_body_size {
+            return Err(body_too_large());
+        }
+        fill_buffer(stream, &mut buffered, position + size + 2).await?;
+        if &buffered[position + size..position + size + 2] != b"\r\n" {
+            return Err(invalid());
+        }
+        body.extend_from_slice(&buffered[position..position + size]);
+        position += size + 2;
+    }
+    // Trailer fields aren't used, they are read up to the blank line and dropped
+    while !read_chunk_line(stream, &mut buffered, &mut position).await?.is_empty() {}
+    *pending = buffered.split_off(position);
+    Ok(body)
+}
+
+async fn read_chunk_line(stream: &mut TcpStream, buffered: &mut Vec<u8>, position: &mut usize) -> io::Result<Vec<u8>> {
+    loop {
+        if let Some(end) = find_bytes(&buffered[*position..], b"\r\n") {
+            let line = buffered[*position..*position + end].to_vec();
+            *position += end + 2;
+            return Ok(line);
+        }
+        if buffered.len() - *position > MAX_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk size line too long"));
+        }
+        let target = buffered.len() + 1;
+        fill_buffer(stream, buffered, target).await?;
+    }
+}
+
+async fn fill_buffer(stream: &mut TcpStream, buffered: &mut Vec<u8>, length: usize) -> io::Result<()> {
+    let mut chunk = [0; 8192];
+    while buffered.len() < length {
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed inside a chunked body"));
+        }
+        buffered.extend_from_slice(&chunk[..size]);
+    }
+    Ok(())
+}
+
+fn reframe_head(head: &[u8], content_length: usize) -> Vec<u8> {
+    // Drops the framing headers of the chunked body and declares the length of the decoded one
+    let head = String::from_utf8_lossy(head);
+    let mut reframed = String::with_capacity(head.len());
+    for line in head.lines().take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').map_or(("", ""), |(name, value)| (name.trim(), value));
+        if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // Codings applied before chunked are kept, so handle_request still answers 501 for "gzip, chunked"
+            let mut codings: Vec<&str> = value.split(',').map(str::trim).collect();
+            codings.pop();
+            if !codings.is_empty() {
+                reframed.push_str(&format!("Transfer-Encoding: {}\r\n", codings.join(", ")));
+            }
+        } else if !name.eq_ignore_ascii_case("Content-Length") {
+            reframed.push_str(line);
+            reframed.push_str("\r\n");
+        }
+    }
+    reframed.push_str(&format!("Content-Length: {}\r\n\r\n", content_length));
+    reframed.into_bytes()
+}
+
+fn declared_content_length(head: &[u8]) -> Option<u64> {
+    // Where the body ends is unknown when a length is malformed or lengths disagree, so these are rejected
+    let mut content_length = None;
+    for (name, value) in String::from_utf8_lossy(head).lines().filter_map(|line| line.split_once(':')) {
+        if name.trim().eq_ignore_ascii_case("Content-Length") {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            let length = value.parse::<u64>().ok()?;
+            if content_length.is_some_and(|previous| previous != length) {
+                return None;
+            }
+            content_length = Some(length);
         }
-        Err(e) => eprintln!("Failed to read from connection: {}", e),
     }
+    Some(content_length.unwrap_or(0))
 }
-fn handle_get_request(full_path: &Path, headers: &[String], client_addr: std::net::IpAddr) -> String {
-    if !full_path.exists() {
-        return http_response(404, "Not Found", None, None);
+
+fn percent_decode(value: &str) -> Option<String> {
+    // None when a "%" isn't followed by two hex digits, or the decoded bytes aren't UTF-8
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
     }
-    if full_path.is_dir() {
-        return generate_directory_listing(full_path);
+    String::from_utf8(decoded).ok()
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+fn allowed_methods(config: &Config, full_path: &Path) -> String {
+    // POST, PATCH and the extension methods only run scripts, so other paths don't list them
+    let mut methods = vec!["GET", "HEAD", "OPTIONS"];
+    if full_path.starts_with(config.scripts_dir()) {
+        methods.extend(["POST", "PATCH"]);
+        methods.extend(config.script_methods.iter().map(String::as_str));
     }
+    methods.join(", ")
+}
 
-    match fs::read(full_path) {
-        Ok(contents) => {
+// Scripts that take PATCH advertise the patch formats configured for them
+fn options_response(config: &Config, full_path: &Path) -> Response {
+    let mut response = Response::status(204, "No Content").header("Allow", allowed_methods(config, full_path));
+    let patch_types = config.accept_patch_for(full_path);
+    if full_path.starts_with(config.scripts_dir()) && !patch_types.is_empty() {
+        response = response.header("Accept-Patch", patch_types.join(", "));
+    }
+    response
+}
+
+fn is_safe_path(path: &str) -> bool {
+    // A null byte truncates the path in C-based filesystem calls
+    if path.contains('\0') {
+        return false;
+    }
+    // After stripping the leading slash, "//etc" would be joined as an absolute path
+    if path.starts_with("//") {
+        return false;
+    }
+    // Drive letters and UNC prefixes are only absolute on Windows
+    if cfg!(windows) {
+        if path.contains("\\\\") {
+            return false;
+        }
+        let has_drive = path.split(['/', '\\']).any(|segment| {
+            let bytes = segment.as_bytes();
+            bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+        });
+        if has_drive {
+            return false;
+        }
+    }
+    true
+}
+
+// Resolves "." and ".." segments without touching the file system, so "/scripts/../evil.sh" is looked up as
+// "/evil.sh" and not as a script. None when the path doesn't start with "/" or a ".." climbs above the root
+fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.strip_prefix('/')?.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+        trailing_slash = matches!(segment, "" | "." | "..");
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+fn is_inside_root(root_folder: &Path, full_path: &Path) -> bool {
+    // Existing paths are resolved, missing ones can't be resolved and only pass without ".." segments
+    match fs::canonicalize(full_path) {
+        Ok(resolved) => resolved.starts_with(root_folder),
+        Err(_) => !full_path.components().any(|component| component == Component::ParentDir),
+    }
+}
+
+fn path_depth(path: &str) -> usize {
+    path.split('/').filter(|segment| !segment.is_empty()).count()
+}
+
+fn matches_case_on_disk(root_folder: &Path, relative_path: &str) -> bool {
+    // Every component must match the name of an entry of its parent directory exactly
+    let mut current = root_folder.to_path_buf();
+    for component in Path::new(relative_path).components() {
+        if let Component::Normal(name) = component {
+            let found = fs::read_dir(&current)
+                .map(|entries| entries.flatten().any(|entry| entry.file_name() == name))
+                .unwrap_or(false);
+            if !found {
+                return false;
+            }
+        }
+        current.push(component);
+    }
+    true
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = %full_path.display())))]
+#[allow(clippy::too_many_arguments)]
+// Returns the status sent and whether the connection stays open, which the request allows with keep_alive
+async fn handle_directory_request(stream: &mut TcpStream, full_path: &Path, url_path: &str, query: &str, headers: &[String], version: &str, extra_headers: &str, keep_alive: bool, send_body: bool, config: &Config) -> (&'static str, bool) {
+    let instance = url_path.split('/').map(percent_encode).collect::<Vec<_>>().join("/");
+    let Some(_slot) = acquire_file_slot(config.max_open_files).await else {
+        let response = with_error_body(config, Response::status(503, "Service Unavailable"), Some(&instance));
+        return ("503", send_listing_response(stream, response, extra_headers, keep_alive, send_body).await);
+    };
+
+    // The JSON index is chosen with ?format=json or an Accept header preferring it
+    let json = config.json_listing
+        && (query_param(query, "format") == Some("json") || get_header(headers, "Accept").is_some_and(prefers_json));
+    let negotiated = |response: Response| if config.json_listing { response.vary("Accept") } else { response };
+
+    let etag = match directory_etag(full_path) {
+        Ok(hash) => format!("W/\"{:016x}{}\"", hash, if json { "-json" } else { "" }),
+        Err(_) => {
+            let response = negotiated(with_error_body(config, Response::status(403, "Forbidden"), Some(&instance)));
+            return ("403", send_listing_response(stream, response, extra_headers, keep_alive, send_body).await);
+        }
+    };
+
+    if let Some(if_none_match) = get_header(headers, "If-None-Match") {
+        if etag_matches(if_none_match, &etag) {
+            let response = negotiated(Response::status(304, "Not Modified").header("ETag", &etag));
+            return ("304", send_listing_response(stream, response, extra_headers, keep_alive, send_body).await);
+        }
+    }
+
+    match fs::read_dir(full_path) {
+        Ok(entries) => {
+            // Without chunked encoding the listing ends when the connection is closed, which its head tells
+            let chunked = version == "HTTP/1.1";
+            let head = negotiated(listing_head(&etag, chunked, json));
+            let keep_alive = keep_alive && !head.ends_at_close();
+            let head = head.extra_headers(extra_headers).extra_headers(connection_header(keep_alive)).into_bytes();
+            let sent = if send_body {
+                stream_directory_listing(stream, url_path, entries, &head, chunked, json).await
+            } else {
+                stream.write_all(&head).await
+            };
+            if let Err(e) = sent {
+                error_log!("Failed to send directory listing: {}", e);
+                return ("200", false);
+            }
+            ("200", keep_alive)
+        }
+        Err(_) => {
+            let response = negotiated(with_error_body(config, Response::status(403, "Forbidden"), Some(&instance)));
+            ("403", send_listing_response(stream, response, extra_headers, keep_alive, send_body).await)
+        }
+    }
+}
+
+// Sends a response that isn't a listing, returning whether the connection stays open
+async fn send_listing_response(stream: &mut TcpStream, response: Response, extra_headers: &str, keep_alive: bool, send_body: bool) -> bool {
+    let extra_headers = format!("{}{}", extra_headers, connection_header(keep_alive));
+    send_response(stream, response, &extra_headers, send_body).await.is_ok() && keep_alive
+}
+
+fn directory_etag(path: &Path) -> io::Result<u64> {
+    // The entry hashes are summed so the validator doesn't depend on the read_dir order
+    let mut combined: u64 = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let mut hasher = DefaultHasher::new();
+        entry.file_name().hash(&mut hasher);
+        if let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) {
+            modified.hash(&mut hasher);
+        }
+        combined = combined.wrapping_add(hasher.finish());
+    }
+    Ok(combined)
+}
+
+fn index_page(method: &str, directory: &Path, headers: &[String], config: &Config) -> Option<PathBuf> {
+    // Directories under /scripts are only ever listed, an index.html there isn't a page
+    if method != "GET" || !directory.is_dir() || directory.starts_with(config.scripts_dir()) {
+        return None;
+    }
+    // Rules apply in order when the Accept header names their media type, "*/*" alone keeps the default page
+    let accept = get_header(headers, "Accept").map(parse_quality_list).unwrap_or_default();
+    config
+        .index_rules
+        .iter()
+        .filter(|(media_type, _)| accept.iter().any(|(value, q)| value.eq_ignore_ascii_case(media_type) && *q > 0.0))
+        .map(|(_, file)| directory.join(file))
+        .chain([directory.join(INDEX_FILE)])
+        .find(|path| path.is_file())
+}
+
+fn meets_required_accept(config: &Config, path: &str, headers: &[String]) -> bool {
+    // Without an Accept header the client accepts any media type
+    let Some(accept) = get_header(headers, "Accept") else {
+        return true;
+    };
+    config
+        .required_accept
+        .iter()
+        .filter(|(prefix, _)| config::is_under_prefix(path, prefix))
+        .all(|(_, media_type)| accepts_media_type(accept, media_type))
+}
+
+fn accepts_media_type(accept: &str, media_type: &str) -> bool {
+    // The most specific matching range decides, so "*/*, application/json;q=0" refuses JSON
+    let media_type = media_type.to_ascii_lowercase();
+    let main_type = media_type.split('/').next().unwrap_or_default();
+    let ranges = parse_quality_list(accept);
+    let quality = |range: &str| ranges.iter().find(|(value, _)| value == range).map(|(_, q)| *q);
+    let best = quality(&media_type)
+        .or_else(|| quality(&format!("{}/*", main_type)))
+        .or_else(|| quality("*/*"));
+    best.is_some_and(|q| q > 0.0)
+}
+
+fn prefers_json(accept: &str) -> bool {
+    let types = parse_quality_list(accept);
+    let quality = |media_type: &str| types.iter().find(|(value, _)| value == media_type).map(|(_, q)| *q);
+    let json = quality("application/json").unwrap_or(0.0);
+    let html = quality("text/html").or_else(|| quality("text/*")).or_else(|| quality("*/*")).unwrap_or(0.0);
+    json > 0.0 && json >= html
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    // If-None-Match uses the weak comparison, so the W/ prefix is ignored
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn is_supported_transfer_encoding(codings: &str) -> bool {
+    codings
+        .split(',')
+        .map(str::trim)
+        .all(|coding| coding.eq_ignore_ascii_case("chunked") || coding.eq_ignore_ascii_case("identity"))
+}
+
+fn identity_refused(accept_encoding: &str) -> bool {
+    // identity is acceptable unless refused by name, or by "*" when it isn't listed
+    let codings = parse_quality_list(accept_encoding);
+    match codings.iter().find(|(coding, _)| coding == "identity") {
+        Some((_, quality)) => *quality == 0.0,
+        None => codings.iter().any(|(coding, quality)| coding == "*" && *quality == 0.0),
+    }
+}
+
+fn accepts_coding(accept_encoding: &str, coding: &str) -> bool {
+    // A coding is acceptable when listed with a non-zero quality, or covered by "*" when it isn't listed
+    let codings = parse_quality_list(accept_encoding);
+    match codings.iter().find(|(name, _)| name.eq_ignore_ascii_case(coding)) {
+        Some((_, quality)) => *quality > 0.0,
+        None => codings.iter().any(|(name, quality)| name == "*" && *quality > 0.0),
+    }
+}
+
+fn parse_quality_list(header: &str) -> Vec<(String, f32)> {
+    // Parses "value;q=0.5, other" into lowercase values and their q-values, 1 when absent
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, q)| q.trim().parse().ok())
+                .unwrap_or(1.0);
+            Some((value, quality))
+        })
+        .collect()
+}
+
+fn get_header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|header| {
+        let (key, value) = header.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = %full_path.display())))]
+// The file itself is left in file_body, to be streamed after the returned response head
+// negotiated_index is set for index pages chosen by the --index-rule rules
+async fn handle_get_request(full_path: &Path, version: &str, headers: &[String], config: &Config, file_body: &mut Option<FileBody>, negotiated_index: bool) -> Response {
+    // A sibling in a modern image format the client accepts is sent instead of the requested image
+    let negotiates_image = config.image_variants && get_mime_type(full_path).is_some_and(|mime_type| IMAGE_VARIANT_SOURCES.contains(&mime_type));
+    let image_variant = if negotiates_image { image_variant(full_path, headers) } else { None };
+    let full_path = image_variant.as_deref().unwrap_or(full_path);
+
+    // A copy from an encoded root is sent instead of the file when the client accepts its coding
+    let variant = get_header(headers, "Accept-Encoding").and_then(|accept_encoding| encoded_variant(config, full_path, accept_encoding));
+    let (file_path, mut coding) = match &variant {
+        Some((coding, _, path)) => (path.as_path(), Some(*coding)),
+        None => (full_path, None),
+    };
+    if !file_path.exists() {
+        return not_found_response(config).await;
+    }
+    // The index page, image variant and encoded copy are picked on disk, a symlink among them must not lead out of its root
+    let root = variant.as_ref().map_or(config.root_folder.as_path(), |(_, root, _)| root.as_path());
+    if !fs::canonicalize(root).is_ok_and(|root| is_inside_root(&root, file_path)) {
+        return Response::status(403, "Forbidden");
+    }
+
+    let Some(slot) = acquire_file_slot(config.max_open_files).await else {
+        return Response::status(503, "Service Unavailable");
+    };
+
+    match open_file(file_path).await {
+        Ok((mut file, metadata)) => {
+            let length = metadata.len();
             let mime_type = get_mime_type(full_path);
             let status_code = 200;
             let status_text = "OK";
 
-            // Log request
-            let method = "GET";  // Assuming this function handles only GET requests
-            let client_ip = client_addr.to_string();  // Obtain client IP from TcpStream
-            let path = full_path.to_str().unwrap_or_default();  // Convert path to string
-            let log_message = format!("{} {} {} -> {} ({})", method, client_ip, path, status_code, status_text);
-            println!("{}", log_message);
+            let rewrite = coding.is_none() && length <= MAX_REWRITE_SIZE && mime_type.is_some_and(|mime_type| config.rewrites_content_type(mime_type));
+            let compress = coding.is_none() && get_header(headers, "Accept-Encoding").is_some_and(|accept_encoding| gzips(config, full_path, accept_encoding));
+            // Each coding is a different representation, so it gets its own validator
+            let etag = file_etag(&metadata, coding.or(compress.then_some("gzip")));
+            let modified = modified_since_epoch(&metadata).as_secs();
+            // If-Modified-Since is only a fallback for clients without an ETag, and dates that don't parse never match
+            let not_modified = match get_header(headers, "If-None-Match") {
+                Some(if_none_match) => etag_matches(if_none_match, &etag),
+                None => get_header(headers, "If-Modified-Since").and_then(parse_http_date).is_some_and(|since| modified <= since),
+            };
+            let mut response = if not_modified {
+                // The client's copy is current, only the headers describing it are sent
+                Response::status(304, "Not Modified")
+            } else if rewrite || (compress && length <= MAX_GZIP_SIZE) {
+                // Rewriting and compressing change the length, so the file is transformed before anything is sent
+                let mut contents = Vec::new();
+                if file.read_to_end(&mut contents).await.is_err() {
+                    return Response::status(403, "Forbidden");
+                }
+                if rewrite {
+                    contents = config.rewrite_body(contents);
+                }
+                if compress {
+                    contents = gzip(&contents);
+                    coding = Some("gzip");
+                }
+                Response::status(status_code, status_text).body(contents)
+            } else if compress {
+                // The compressed length is only known at the end, so the body is chunked, or ends when the
+                // connection is closed for HTTP/1.0 clients
+                let chunked = version == "HTTP/1.1";
+                *file_body = Some(FileBody { file, length, gzip: true, chunked, _slot: slot });
+                coding = Some("gzip");
+                let response = Response::status(status_code, status_text);
+                if chunked { response.chunked() } else { response.without_length() }
+            } else {
+                *file_body = Some(FileBody { file, length, gzip: false, chunked: false, _slot: slot });
+                Response::status(status_code, status_text).content_length(length)
+            };
+            if let Some(mime_type) = mime_type.filter(|_| !not_modified) {
+                response = response.header("Content-Type", mime_type);
+            }
+            if let Some(coding) = coding.filter(|_| !not_modified) {
+                response = response.header("Content-Encoding", coding);
+            }
+            response = response.header("ETag", etag).header("Last-Modified", http_date(modified));
+            if !config.encoded_roots.is_empty() || (config.gzip && mime_type.is_some_and(|mime_type| config.compresses_content_type(mime_type))) {
+                response = response.vary("Accept-Encoding");
+            }
+            if negotiates_image {
+                response = response.vary("Accept");
+            }
+            if let Some(location) = content_location(config, full_path, variant.as_ref(), image_variant.is_some() || negotiated_index) {
+                response = response.header("Content-Location", location);
+            }
+            response
+        }
+        Err(_) => Response::status(403, "Forbidden"), // Changed to 403 for file read errors
+    }
+}
+
+// Names the representation that negotiation chose, so clients and caches can address it directly
+// An encoded copy only has a URL of its own when its root is inside the root folder
+fn content_location(config: &Config, full_path: &Path, variant: Option<&(&str, &PathBuf, PathBuf)>, chose_file: bool) -> Option<String> {
+    let copy_path = variant.and_then(|(_, _, copy)| fs::canonicalize(copy).ok());
+    let copy_path = copy_path.as_deref().and_then(|copy| copy.strip_prefix(&config.root_folder).ok());
+    if let Some(segments) = copy_path.and_then(|path| path.iter().map(|segment| segment.to_str().map(percent_encode)).collect::<Option<Vec<_>>>()) {
+        return Some(format!("/{}", segments.join("/")));
+    }
+    // Relative to the request, so it names the sibling or the index page in the requested directory
+    let name = full_path.file_name()?.to_str()?;
+    chose_file.then(|| percent_encode(name))
+}
+
+async fn open_file(path: &Path) -> io::Result<(tokio::fs::File, fs::Metadata)> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    Ok((file, metadata))
+}
+
+fn modified_since_epoch(metadata: &fs::Metadata) -> Duration {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+}
 
-            http_response(status_code, status_text, mime_type, Some(&contents))
+fn file_etag(metadata: &fs::Metadata, coding: Option<&str>) -> String {
+    // Size and modification time change whenever the file is rewritten, without reading it
+    let modified = modified_since_epoch(metadata);
+    let suffix = coding.map(|coding| format!("-{}", coding)).unwrap_or_default();
+    format!("\"{:x}-{:x}{}\"", metadata.len(), modified.as_nanos(), suffix)
+}
+
+// A file sent as a response body, holding its --max-open-files slot until it has been sent
+struct FileBody {
+    file: tokio::fs::File,
+    length: u64,
+    // Compressed while it's sent, in chunks when chunked
+    gzip: bool,
+    chunked: bool,
+    _slot: FileSlot,
+}
+
+async fn send_file_body(stream: &mut (impl AsyncWrite + Unpin), mut body: FileBody, max_rate: u64) -> io::Result<()> {
+    // Exactly the announced length is sent, a file that shrank meanwhile can only be cut off
+    // With a rate, chunks are at most a second's worth and the pause before the next one is taken
+    // between writes, so a slow client and the throttling don't add up in a single write
+    let chunk_size = if max_rate > 0 { FILE_CHUNK_SIZE.min(max_rate as usize) } else { FILE_CHUNK_SIZE };
+    let mut chunk = vec![0; chunk_size];
+    let started = tokio::time::Instant::now();
+    let mut encoder = body.gzip.then(|| GzEncoder::new(Vec::new(), Compression::default()));
+    let mut remaining = body.length;
+    let mut sent = 0;
+    while remaining > 0 {
+        let wanted = remaining.min(chunk_size as u64) as usize;
+        let size = body.file.read(&mut chunk[..wanted]).await?;
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while it was sent"));
+        }
+        remaining -= size as u64;
+        sent += match &mut encoder {
+            Some(encoder) => {
+                // Writing to a Vec can't fail, whatever the encoder produced so far is sent and dropped
+                encoder.write_all(&chunk[..size]).unwrap();
+                let compressed = mem::take(encoder.get_mut());
+                write_body_part(stream, &compressed, body.chunked).await?;
+                compressed.len()
+            }
+            None => {
+                stream.write_all(&chunk[..size]).await?;
+                size
+            }
+        };
+        if max_rate > 0 && remaining > 0 {
+            tokio::time::sleep_until(started + Duration::from_secs_f64(sent as f64 / max_rate as f64)).await;
+        }
+    }
+    if let Some(encoder) = encoder {
+        write_body_part(stream, &encoder.finish().unwrap(), body.chunked).await?;
+        if body.chunked {
+            stream.write_all(b"0\r\n\r\n").await?;
         }
-        Err(_) => http_response(403, "Forbidden", None, None), // Changed to 403 for file read errors
     }
+    Ok(())
+}
+
+// An empty part is skipped, as a chunk it would end the body
+async fn write_body_part(stream: &mut (impl AsyncWrite + Unpin), part: &[u8], chunked: bool) -> io::Result<()> {
+    if part.is_empty() {
+        return Ok(());
+    }
+    if chunked {
+        stream.write_all(format!("{:x}\r\n", part.len()).as_bytes()).await?;
+        stream.write_all(part).await?;
+        stream.write_all(b"\r\n").await
+    } else {
+        stream.write_all(part).await
+    }
+}
+
+fn image_variant(full_path: &Path, headers: &[String]) -> Option<PathBuf> {
+    // Only image types the Accept header names count, browsers that support them list them explicitly
+    let accept = parse_quality_list(get_header(headers, "Accept")?);
+    IMAGE_VARIANTS
+        .iter()
+        .filter(|(media_type, _)| accept.iter().any(|(value, q)| value == media_type && *q > 0.0))
+        .map(|(_, extension)| full_path.with_extension(extension))
+        .find(|path| path.is_file())
 }
 
-fn handle_post_request(full_path: &Path, headers: &[String], body: &[u8]) -> String {
+// Whether --gzip compresses the file for a client with this Accept-Encoding
+fn gzips(config: &Config, full_path: &Path, accept_encoding: &str) -> bool {
+    config.gzip
+        && get_mime_type(full_path).is_some_and(|mime_type| config.compresses_content_type(mime_type))
+        && accepts_coding(accept_encoding, "gzip")
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Returns the coding, the encoded root and the copy in it
+fn encoded_variant<'a>(config: &'a Config, full_path: &Path, accept_encoding: &str) -> Option<(&'a str, &'a PathBuf, PathBuf)> {
+    // The roots are tried in the order they were configured
+    let relative_path = full_path.strip_prefix(&config.root_folder).ok()?;
+    config
+        .encoded_roots
+        .iter()
+        .filter(|(coding, _)| accepts_coding(accept_encoding, coding))
+        .map(|(coding, root)| (coding.as_str(), root, root.join(relative_path)))
+        .find(|(_, _, path)| path.is_file() && !is_stale_copy(path, full_path))
+}
+
+// A copy older than the file it was made from would serve outdated content, the next root or the file itself is used instead
+fn is_stale_copy(copy: &Path, source: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).ok().map(|metadata| modified_since_epoch(&metadata));
+    let stale = matches!((modified(copy), modified(source)), (Some(copy), Some(source)) if copy < source);
+    if stale {
+        error_log!("Skipping {}, it is older than {}", copy.display(), source.display());
+    }
+    stale
+}
+
+// Released when dropped
+struct FileSlot;
+
+impl Drop for FileSlot {
+    fn drop(&mut self) {
+        OPEN_FILES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn acquire_file_slot(limit: usize) -> Option<FileSlot> {
+    // A limit of 0 means unlimited, the count is still kept
+    let deadline = Instant::now() + FILE_SLOT_WAIT;
+    loop {
+        let acquired = OPEN_FILES
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (limit == 0 || open < limit).then_some(open + 1))
+            .is_ok();
+        if acquired {
+            return Some(FileSlot);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+async fn not_found_response(config: &Config) -> Response {
+    // The custom page keeps the 404 status, only the body is replaced
+    if config.not_found_page {
+        if let Ok(contents) = tokio::fs::read(config.root_folder.join("404.html")).await {
+            return Response::status(404, "Not Found").header("Content-Type", "text/html").body(contents);
+        }
+    }
+    Response::status(404, "Not Found")
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = %full_path.display())))]
+fn handle_post_request(method: &str, full_path: &Path, headers: &[String], body: &[u8], config: &Config) -> Response {
     if !full_path.exists() || !full_path.is_file() {
-        return http_response(404, "Not Found", None, None);
+        return Response::status(404, "Not Found");
+    }
+
+    if !full_path.starts_with(config.scripts_dir()) {
+        return Response::status(403, "Forbidden");
     }
 
-    if !full_path.starts_with("scripts") {
-        return http_response(403, "Forbidden", None, None);
+    if !config.accepts_script_content_type(full_path, get_header(headers, "Content-Type")) {
+        return Response::status(415, "Unsupported Media Type");
     }
 
+    let decoded_body = match decode_request_body(body, headers, config) {
+        Ok(decoded_body) => decoded_body,
+        Err((status_code, status_text)) => return Response::status(status_code, status_text),
+    };
+
     let mut command = Command::new(full_path);
     for header in headers {
         if let Some((key, value)) = header.split_once(':') {
-            command.env(key.trim(), value.trim());
+            if let Some(name) = header_env_name(key.trim(), config) {
+                command.env(name, value.trim());
+            }
+        }
+    }
+
+    if let Some(decoded_body) = &decoded_body {
+        // The script gets the decoded body, so the headers describe that one
+        command.env_remove("HTTP_CONTENT_ENCODING");
+        if config.passes_header_to_scripts("Content-Length") {
+            command.env("HTTP_CONTENT_LENGTH", decoded_body.len().to_string());
         }
     }
+    let body = decoded_body.as_deref().unwrap_or(body);
 
-    command.env("Method", "POST");
-    command.env("Path", full_path.to_str().unwrap());
+    command.env("Method", method);
+    command.env("REQUEST_METHOD", method);
+    command.env("Path", full_path);
+    // Configured variables come last, so they can override the request ones
+    for (name, value) in config.script_env_for(full_path) {
+        command.env(name, value);
+    }
     command.stdin(Stdio::piped());
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+
+    let body = match config.script_stdin_newlines_for(full_path) {
+        Some(line_ending) => normalize_line_endings(body, line_ending),
+        None => body.to_vec(),
+    };
+
+    // Scripts that can't be started, like ones missing the execute permission, are a server error
+    let output = match run_script(&mut command, body, config.script_first_byte_timeout_for(full_path)) {
+        Ok(Some(output)) => output,
+        Ok(None) => return Response::status(504, "Gateway Timeout"),
+        Err(e) => {
+            error_log!("Failed to run script {}: {}", full_path.display(), e);
+            return Response::status(500, "Internal Server Error");
+        }
+    };
+    if !output.status.success() {
+        return Response::status(500, "Internal Server Error").body(output.stderr);
+    }
+
+    // Output starting with a CGI header block sets the response headers, anything else is all body
+    let (script_headers, body) = match parse_script_headers(&output.stdout, config.max_script_header_size, config.max_script_headers) {
+        Some(Ok(parsed)) => parsed,
+        Some(Err(ScriptHeadersTooLarge)) => {
+            error_log!("Script {} sent a header block over the limits", full_path.display());
+            return Response::status(502, "Bad Gateway");
+        }
+        None => (Vec::new(), &output.stdout[..]),
+    };
+    let mut status = None;
+    let mut content_type = None;
+    let mut passed_headers = Vec::new();
+    for (name, value) in &script_headers {
+        if name.eq_ignore_ascii_case("Status") {
+            let Some(parsed) = parse_script_status(value) else {
+                error_log!("Script {} sent an invalid Status: {}", full_path.display(), value);
+                return Response::status(500, "Internal Server Error");
+            };
+            status = Some(parsed);
+        } else if name.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(value.as_str());
+        } else if !SERVER_OWNED_HEADERS.iter().any(|owned| name.eq_ignore_ascii_case(owned)) {
+            passed_headers.push((name, value));
+        }
+    }
+    // Like in CGI, a Location without a Status is a redirect
+    let has_location = script_headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Location"));
+    let (status_code, status_text) = match status {
+        Some(status) => status,
+        None if has_location => (302, "Found".to_string()),
+        None if body.is_empty() && config.empty_script_status == 204 => (204, "No Content".to_string()),
+        None => (200, "OK".to_string()),
+    };
+    // 204 and 304 responses drop the body
+    let mut response = Response::status(status_code, &status_text).body(body);
+    if let Some(content_type) = content_type {
+        response = response.header("Content-Type", content_type);
+    }
+    // Browsers must not sniff script output into an executable type, unless the script sets its own value
+    let sets_nosniff = passed_headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("X-Content-Type-Options"));
+    for (name, value) in passed_headers {
+        response = response.header(name, value);
+    }
+    if config.script_nosniff && !sets_nosniff {
+        response = response.header("X-Content-Type-Options", "nosniff");
+    }
+    response
+}
+
+type ScriptHeaders = Vec<(String, String)>;
+
+struct ScriptHeadersTooLarge;
+
+fn parse_script_headers(output: &[u8], max_size: usize, max_headers: usize) -> Option<Result<(ScriptHeaders, &[u8]), ScriptHeadersTooLarge>> {
+    // Header lines end with LF or CRLF, the block ends with an empty line, and every line has to be a
+    // "Name: value" header, so plain output that happens to contain a colon isn't taken for headers
+    // A block past the limits is an error rather than body, it is stopped before it's collected whole
+    let mut headers = Vec::new();
+    let mut rest = output;
+    loop {
+        let line_end = rest.iter().position(|&byte| byte == b'\n')?;
+        let line = std::str::from_utf8(&rest[..line_end]).ok()?;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        rest = &rest[line_end + 1..];
+        if line.is_empty() {
+            return (!headers.is_empty()).then_some(Ok((headers, rest)));
+        }
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if !is_token(name) || value.chars().any(|c| c.is_control() && c != '\t') {
+            return None;
+        }
+        if output.len() - rest.len() > max_size || headers.len() == max_headers {
+            return Some(Err(ScriptHeadersTooLarge));
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+}
+
+fn parse_script_status(value: &str) -> Option<(u16, String)> {
+    // "404 Not Found", the reason phrase defaults to the standard one
+    let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+    let status_code = code.parse().ok().filter(|code| (200..600).contains(code))?;
+    let reason = match reason.trim() {
+        "" => get_status_text(code).to_string(),
+        reason => reason.to_string(),
+    };
+    Some((status_code, reason))
+}
+
+fn run_script(command: &mut Command, body: Vec<u8>, first_byte_timeout: Option<Duration>) -> io::Result<Option<Output>> {
+    let mut child = command.spawn()?;
+    let (Some(mut stdin), Some(mut stdout), Some(mut stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+        return child.wait_with_output().map(Some);
+    };
+
+    // The body is written while the output is read, so neither pipe can fill up and block the script.
+    // A script may exit without reading its input, which isn't an error
+    let stdin_writer = thread::spawn(move || match stdin.write_all(&body) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(()),
+    });
+
+    // The stdout reader reports when the script first writes (or closes) its output
+    let (first_output, first_output_received) = mpsc::channel();
+    let stdout_reader = thread::spawn(move || -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut chunk = [0; 8192];
+        loop {
+            let size = stdout.read(&mut chunk)?;
+            let _ = first_output.send(());
+            if size == 0 {
+                return Ok(output);
+            }
+            output.extend_from_slice(&chunk[..size]);
+        }
+    });
+    let stderr_reader = thread::spawn(move || -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        stderr.read_to_end(&mut output)?;
+        Ok(output)
+    });
+
+    if let Some(timeout) = first_byte_timeout {
+        if first_output_received.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+            // A script that hasn't started answering is considered hung
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+    }
 
-    let mut child = command.spawn().unwrap();
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin.write_all(body).unwrap();
+    let stdout = join_pipe(stdout_reader)?;
+    let stderr = join_pipe(stderr_reader)?;
+    join_pipe(stdin_writer)?;
+    let status = child.wait()?;
+    Ok(Some(Output { status, stdout, stderr }))
+}
+
+fn join_pipe<T>(handle: thread::JoinHandle<io::Result<T>>) -> io::Result<T> {
+    handle.join().unwrap_or_else(|_| Err(io::Error::other("script pipe thread panicked")))
+}
+
+// None when the body is passed as it was sent, the error is the status to answer with
+fn decode_request_body(body: &[u8], headers: &[String], config: &Config) -> Result<Option<Vec<u8>>, (u16, &'static str)> {
+    let Some(coding) = get_header(headers, "Content-Encoding").filter(|_| config.decompress_request_bodies) else {
+        return Ok(None);
+    };
+    match coding.trim().to_ascii_lowercase().as_str() {
+        "identity" => Ok(None),
+        "gzip" | "x-gzip" => {
+            // Reading stops just past the limit, so a small body can't expand into unbounded memory
+            let mut limit = config.max_decompressed_body;
+            if config.max_decompression_ratio > 0 {
+                limit = limit.min((body.len() as u64).saturating_mul(config.max_decompression_ratio));
+            }
+            let mut decoded = Vec::new();
+            let read = MultiGzDecoder::new(body).take(limit + 1).read_to_end(&mut decoded);
+            match read {
+                Ok(_) if decoded.len() as u64 > limit => Err((413, "Payload Too Large")),
+                Ok(_) => Ok(Some(decoded)),
+                Err(_) => Err((400, "Bad Request")),
+            }
+        }
+        // Codings that can't be decoded, like br or several stacked ones
+        _ => Err((415, "Unsupported Media Type")),
+    }
+}
+
+fn header_env_name(header: &str, config: &Config) -> Option<String> {
+    if !config.passes_header_to_scripts(header) {
+        return None;
     }
+    // Only token characters are kept, so a header can't produce an arbitrary variable name
+    if header.is_empty() || !header.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    Some(format!("HTTP_{}", header.to_ascii_uppercase().replace('-', "_")))
+}
 
-    let output = child.wait_with_output().unwrap();
-    if output.status.success() {
-        http_response(200, "OK", None, Some(&output.stdout))
+fn normalize_line_endings(body: &[u8], line_ending: LineEnding) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(body.len());
+    let mut bytes = body.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        // CRLF is collapsed to LF first, lone CRs are left alone
+        if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+            continue;
+        }
+        if byte == b'\n' && line_ending == LineEnding::Crlf {
+            normalized.push(b'\r');
+        }
+        normalized.push(byte);
+    }
+    normalized
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// With --error-format problem+json, an error response without a body of its own gets an RFC 7807 one
+// instance is the request path as sent, unknown when the request couldn't be read
+fn with_error_body(config: &Config, response: Response, instance: Option<&str>) -> Response {
+    if config.error_format != ErrorFormat::ProblemJson || response.status_code() < 400 || response.has_body() {
+        return response;
+    }
+    let status = response.status_code();
+    // With the about:blank type, the title is the status text
+    let mut fields = vec![
+        format!("\"type\":{}", json_string("about:blank")),
+        format!("\"title\":{}", json_string(response.reason())),
+        format!("\"status\":{}", status),
+    ];
+    if let Some(detail) = problem_detail(status) {
+        fields.push(format!("\"detail\":{}", json_string(detail)));
+    }
+    if let Some(instance) = instance {
+        fields.push(format!("\"instance\":{}", json_string(instance)));
+    }
+    response.header("Content-Type", "application/problem+json").body(format!("{{{}}}", fields.join(",")))
+}
+
+fn problem_detail(status: u16) -> Option<&'static str> {
+    match status {
+        400 => Some("The request is malformed."),
+        403 => Some("The resource may not be accessed."),
+        404 => Some("No resource exists at this path."),
+        405 => Some("The resource doesn't support this method."),
+        406 => Some("The resource has no representation the request accepts."),
+        408 => Some("The request wasn't received in time."),
+        413 => Some("The request body is larger than the server accepts."),
+        415 => Some("The request body is in an encoding the server doesn't support."),
+        500 => Some("The server failed to handle the request."),
+        501 => Some("The request uses a feature the server doesn't implement."),
+        502 => Some("The script sent an invalid response."),
+        503 => Some("The server is too busy to handle the request."),
+        504 => Some("The script didn't answer in time."),
+        _ => None,
+    }
+}
+
+async fn send_response(stream: &mut TcpStream, response: Response, extra_headers: &str, send_body: bool) -> io::Result<()> {
+    // Without the body, the response ends with the blank line after the headers
+    let response = response.extra_headers(extra_headers);
+    let response = if send_body { response } else { response.head_only() };
+    stream.write_all(&response.into_bytes()).await
+}
+
+fn listing_head(etag: &str, chunked: bool, json: bool) -> Response {
+    // The listing is streamed after the head, so its length isn't known
+    let content_type = if json { "application/json" } else { "text/html; charset=utf-8" };
+    let head = Response::status(200, "OK").header("Content-Type", content_type).header("ETag", etag);
+    if chunked { head.chunked() } else { head.without_length() }
+}
+
+async fn stream_directory_listing(stream: &mut TcpStream, url_path: &str, entries: fs::ReadDir, head: &[u8], chunked: bool, json: bool) -> io::Result<()> {
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(head).await?;
+
+    if json {
+        write_json_listing(&mut writer, entries, chunked).await?;
     } else {
-        http_response(500, "Internal Server Error", None, Some(&output.stderr))
+        write_html_listing(&mut writer, url_path, entries, chunked).await?;
     }
+
+    if chunked {
+        writer.write_all(b"0\r\n\r\n").await?;
+    }
+    writer.flush().await
 }
 
-fn http_response(status_code: u16, status_text: &str, content_type: Option<&str>, body: Option<&[u8]>) -> String {
-    let mut response = format!("HTTP/1.0 {} {}\r\n", status_code, status_text);
-    if let Some(content_type) = content_type {
-        response.push_str(&format!("Content-Type: {}\r\n", content_type));
+async fn write_html_listing(writer: &mut (impl AsyncWrite + Unpin), url_path: &str, entries: fs::ReadDir, chunked: bool) -> io::Result<()> {
+    let title = format!("Index of {}", html_escape(url_path));
+    write_chunk(writer, &format!("<html><head><title>{0}</title></head><body><h1>{0}</h1><table>", title), chunked).await?;
+    write_chunk(writer, "<tr><th>Name</th><th>Size</th><th>Modified</th></tr>", chunked).await?;
+    if url_path != "/" {
+        write_chunk(writer, "<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>", chunked).await?;
     }
-    response.push_str("Connection: close\r\n\r\n");
-    if let Some(body) = body {
-        response.push_str(&String::from_utf8_lossy(body));
+
+    // Entries are written in read_dir order, without sorting, so memory stays bounded for huge directories
+    for entry in entries {
+        let entry = entry?;
+        if httpconfig::is_config_file(&entry.path()) {
+            continue;
+        }
+        // Links are relative to the directory, directories get a trailing slash like their own URLs
+        let metadata = fs::metadata(entry.path()).ok();
+        let is_dir = metadata.as_ref().is_some_and(|metadata| metadata.is_dir());
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let suffix = if is_dir { "/" } else { "" };
+        let size = match &metadata {
+            Some(metadata) if !is_dir => metadata.len().to_string(),
+            _ => "-".to_string(),
+        };
+        let modified = metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(String::new, |since_epoch| format_timestamp(since_epoch.as_secs()));
+        let row = format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encode(&name),
+            suffix,
+            html_escape(&name),
+            suffix,
+            size,
+            modified
+        );
+        write_chunk(writer, &row, chunked).await?;
     }
-    response
+
+    write_chunk(writer, "</table></body></html>", chunked).await
+}
+
+fn percent_encode(value: &str) -> String {
+    // Everything but unreserved characters is escaped, so names are safe as a path segment
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_timestamp(seconds: u64) -> String {
+    // Seconds since the epoch as "YYYY-MM-DD HH:MM" in UTC
+    let (year, month, day) = civil_from_days(seconds / 86400);
+    let time_of_day = seconds % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, time_of_day / 3600, time_of_day % 3600 / 60)
 }
 
-fn generate_directory_listing(path: &Path) -> String {
-    let mut response = String::new();
-    response.push_str("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\n\r\n");
-    response.push_str("<html><h1>Directory listing</h1><ul>");
-    response.push_str(&format!("<li><a href=\"{}\">..</a></li>", path.parent().unwrap().display()));
+fn http_date(seconds: u64) -> String {
+    // The IMF-fixdate of RFC 7231, like "Sun, 06 Nov 1994 08:49:37 GMT"
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = seconds / 86400;
+    let (year, month, day) = civil_from_days(days);
+    let time_of_day = seconds % 86400;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
 
-    for entry in fs::read_dir(path).unwrap() {
-        let entry = entry.unwrap();
-        let entry_path = entry.path();
-        response.push_str(&format!(
-            "<li><a href=\"/{}\">{}</a></li>",
-            entry_path.display(),
-            entry_path.file_name().unwrap().to_string_lossy()
-        ));
+fn parse_http_date(value: &str) -> Option<u64> {
+    // IMF-fixdate, and the obsolete RFC 850 and asctime formats recipients still have to accept
+    const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+    let parts: Vec<&str> = value.split([' ', ',', '-']).filter(|part| !part.is_empty()).collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, zone] if zone.eq_ignore_ascii_case("GMT") => (day, month, year, time),
+        [_, month, day, time, year] => (day, month, year, time),
+        _ => return None,
+    };
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|name| name.eq_ignore_ascii_case(month))? as u64 + 1;
+    let year: u64 = match year.parse().ok()? {
+        // Two-digit RFC 850 years
+        year @ 0..=69 => year + 2000,
+        year @ 70..=99 => year + 1900,
+        year => year,
+    };
+    let mut clock = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) = (clock.next(), clock.next(), clock.next(), clock.next()) else {
+        return None;
+    };
+    // Four-digit years keep the arithmetic below far from overflowing
+    if !(1970..=9999).contains(&year) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
     }
+    Some(days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
 
-    response.push_str("</ul></html>");
-    response
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // The inverse of civil_from_days, for dates from 1970 on
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: u64) -> (i64, i64, i64) {
+    // Days since the epoch as (year, month, day), with Howard Hinnant's civil_from_days
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+// Header lines sent with every response
+fn standard_headers(config: &Config) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut headers = format!("Date: {}\r\n", http_date(now.as_secs()));
+    if config.server_header {
+        headers.push_str(concat!("Server: rustywebserver/", env!("CARGO_PKG_VERSION"), "\r\n"));
+    }
+    headers
+}
+
+async fn write_json_listing(writer: &mut (impl AsyncWrite + Unpin), entries: fs::ReadDir, chunked: bool) -> io::Result<()> {
+    write_chunk(writer, "[", chunked).await?;
+    let entries = entries.filter(|entry| !entry.as_ref().is_ok_and(|entry| httpconfig::is_config_file(&entry.path())));
+    for (index, entry) in entries.enumerate() {
+        let entry = entry?;
+        // Symlinks are described by their target, like in the HTML listing
+        let metadata = fs::metadata(entry.path()).ok();
+        let kind = match &metadata {
+            Some(metadata) if metadata.is_dir() => "directory",
+            Some(_) => "file",
+            None => "unknown",
+        };
+        let size = metadata.as_ref().map_or("null".to_string(), |metadata| metadata.len().to_string());
+        let mtime = metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or("null".to_string(), |since_epoch| since_epoch.as_secs().to_string());
+        let row = format!(
+            "{}{{\"name\":{},\"type\":\"{}\",\"size\":{},\"mtime\":{}}}",
+            if index == 0 { "" } else { "," },
+            json_string(&entry.file_name().to_string_lossy()),
+            kind,
+            size,
+            mtime
+        );
+        write_chunk(writer, &row, chunked).await?;
+    }
+    write_chunk(writer, "]", chunked).await
+}
+
+fn json_string(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len() + 2);
+    encoded.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => encoded.push_str("\\\""),
+            '\\' => encoded.push_str("\\\\"),
+            '\n' => encoded.push_str("\\n"),
+            '\r' => encoded.push_str("\\r"),
+            '\t' => encoded.push_str("\\t"),
+            c if c.is_control() => encoded.push_str(&format!("\\u{:04x}", c as u32)),
+            c => encoded.push(c),
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
+async fn write_chunk(writer: &mut (impl AsyncWrite + Unpin), data: &str, chunked: bool) -> io::Result<()> {
+    if chunked {
+        writer.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+        writer.write_all(data.as_bytes()).await?;
+        writer.write_all(b"\r\n").await
+    } else {
+        writer.write_all(data.as_bytes()).await
+    }
 }
 
 fn get_status_text(status_code: &str) -> &str {
     match status_code {
         "200" => "OK",
+        "204" => "No Content",
+        "301" => "Moved Permanently",
+        "302" => "Found",
+        "304" => "Not Modified",
+        "400" => "Bad Request",
         "403" => "Forbidden",
         "404" => "Not Found",
         "405" => "Method Not Allowed",
+        "406" => "Not Acceptable",
+        "408" => "Request Timeout",
+        "413" => "Payload Too Large",
+        "415" => "Unsupported Media Type",
         "500" => "Internal Server Error",
+        "501" => "Not Implemented",
+        "502" => "Bad Gateway",
+        "503" => "Service Unavailable",
+        "504" => "Gateway Timeout",
         _ => "Unknown Status",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("/a%20b").as_deref(), Some("/a b"));
+        assert_eq!(percent_decode("/%C3%A9t%c3%a9").as_deref(), Some("/été"));
+        assert_eq!(percent_decode("/plain").as_deref(), Some("/plain"));
+    }
+
+    #[test]
+    fn percent_decode_rejects_malformed_escapes() {
+        assert_eq!(percent_decode("/%"), None);
+        assert_eq!(percent_decode("/%2"), None);
+        assert_eq!(percent_decode("/%zz"), None);
+        // Not UTF-8 once decoded
+        assert_eq!(percent_decode("/%ff"), None);
+    }
+
+    #[test]
+    fn normalize_path_resolves_dot_segments() {
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/a/./b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/scripts/../evil.sh").as_deref(), Some("/evil.sh"));
+        assert_eq!(normalize_path("/a//b/").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize_path("/a/b/..").as_deref(), Some("/a/"));
+        assert_eq!(normalize_path("/a/..").as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn normalize_path_rejects_escapes_and_relative_targets() {
+        assert_eq!(normalize_path("/.."), None);
+        assert_eq!(normalize_path("/a/../../etc/passwd"), None);
+        assert_eq!(normalize_path("*"), None);
+        assert_eq!(normalize_path("é"), None);
+        assert_eq!(normalize_path(""), None);
+    }
+
+    #[test]
+    fn is_inside_root_checks_resolved_paths() {
+        let root = env::temp_dir().join(format!("rustywebserver-root-{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        assert!(is_inside_root(&root, &root.join("dir")));
+        assert!(!is_inside_root(&root, &root.join("dir/../..")));
+        // Missing paths can't be resolved, so they only pass without ".." segments
+        assert!(is_inside_root(&root, &root.join("missing.txt")));
+        assert!(!is_inside_root(&root, &root.join("missing/../../x")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // A fresh directory under the temp dir, resolved like the server resolves its root folder
+    fn temp_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("rustywebserver-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::canonicalize(&root).unwrap()
+    }
+
+    fn test_config(root: &Path, options: &[&str]) -> Config {
+        let mut args = vec!["rustywebserver".to_string(), "8000".to_string(), root.display().to_string()];
+        args.extend(options.iter().map(|option| option.to_string()));
+        Config::from_args(&args).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_pages_and_variants_outside_the_root_are_forbidden() {
+        let outside = temp_root("outside");
+        fs::write(outside.join("outside.txt"), "secret").unwrap();
+        let root = temp_root("symlinked-index");
+        fs::create_dir(root.join("dir")).unwrap();
+        std::os::unix::fs::symlink(outside.join("outside.txt"), root.join("dir/index.html")).unwrap();
+        fs::create_dir(root.join("img")).unwrap();
+        fs::write(root.join("img/photo.jpg"), "jpeg").unwrap();
+        std::os::unix::fs::symlink(outside.join("outside.txt"), root.join("img/photo.webp")).unwrap();
+        fs::write(root.join("page.html"), "inside").unwrap();
+        let config = test_config(&root, &["--image-variants"]);
+
+        let index = index_page("GET", &root.join("dir"), &[], &config).unwrap();
+        assert_eq!(handle_get_request(&index, "HTTP/1.1", &[], &config, &mut None, false).await.status_code(), 403);
+        let accept_webp = ["Accept: image/webp".to_string()];
+        assert_eq!(handle_get_request(&root.join("img/photo.jpg"), "HTTP/1.1", &accept_webp, &config, &mut None, false).await.status_code(), 403);
+        assert_eq!(handle_get_request(&root.join("img/photo.jpg"), "HTTP/1.1", &[], &config, &mut None, false).await.status_code(), 200);
+        assert_eq!(handle_get_request(&root.join("page.html"), "HTTP/1.1", &[], &config, &mut None, false).await.status_code(), 200);
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn declared_content_length_defaults_to_zero() {
+        assert_eq!(declared_content_length(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"), Some(0));
+        assert_eq!(declared_content_length(b"POST / HTTP/1.1\r\ncontent-length:  12 \r\n\r\n"), Some(12));
+    }
+
+    #[test]
+    fn declared_content_length_rejects_ambiguous_lengths() {
+        assert_eq!(declared_content_length(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\n"), None);
+        assert_eq!(declared_content_length(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\n"), Some(2));
+        assert_eq!(declared_content_length(b"POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\n"), None);
+        assert_eq!(declared_content_length(b"POST / HTTP/1.1\r\nContent-Length:\r\n\r\n"), None);
+        assert_eq!(declared_content_length(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn parse_http_date_accepts_all_three_formats() {
+        // The example date of RFC 7231
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(784111777));
+        assert_eq!(parse_http_date(&http_date(784111777)), Some(784111777));
+    }
+
+    #[test]
+    fn parse_http_date_rejects_invalid_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse_http_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 99999999999999 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 10000 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT"), Some(253402300799));
+    }
+
+    #[test]
+    fn parse_script_headers_splits_headers_from_body() {
+        let (headers, body) = parse_script_headers(b"Status: 201 Created\r\nX-A: 1\n\nbody", 1024, 10).unwrap().ok().unwrap();
+        assert_eq!(headers, [("Status".to_string(), "201 Created".to_string()), ("X-A".to_string(), "1".to_string())]);
+        assert_eq!(body, b"body");
+        // Plain output with a colon isn't a header block
+        assert!(parse_script_headers(b"note: this is text\nand more", 1024, 10).is_none());
+    }
+
+    #[test]
+    fn parse_script_headers_caps_the_header_block() {
+        let many_lines: &[u8] = b"A: 1\nB: 2\nC: 3\n\nbody";
+        assert!(parse_script_headers(many_lines, 1024, 3).unwrap().is_ok());
+        assert!(parse_script_headers(many_lines, 1024, 2).unwrap().is_err());
+        let long_value = format!("X-Long: {}\n\nbody", "a".repeat(2000));
+        assert!(parse_script_headers(long_value.as_bytes(), 1024, 10).unwrap().is_err());
+        assert!(parse_script_headers(long_value.as_bytes(), 4096, 10).unwrap().is_ok());
+    }
+
+    #[test]
+    fn reframe_head_keeps_codings_before_chunked() {
+        let head = reframe_head(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n", 4);
+        assert_eq!(head, b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 4\r\n\r\n");
+        let head = reframe_head(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 9\r\n\r\n", 4);
+        assert_eq!(head, b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\n");
+    }
+
+    async fn file_body(name: &str, contents: &[u8]) -> FileBody {
+        let path = env::temp_dir().join(format!("rustywebserver-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        fs::remove_file(&path).unwrap();
+        FileBody { file, length: contents.len() as u64, gzip: false, chunked: false, _slot: acquire_file_slot(0).await.unwrap() }
+    }
+
+    #[tokio::test]
+    async fn send_file_body_sends_the_whole_file() {
+        let contents: Vec<u8> = (0..200_000u32).map(|byte| byte as u8).collect();
+        let mut sent = Vec::new();
+        send_file_body(&mut sent, file_body("whole", &contents).await, 0).await.unwrap();
+        assert_eq!(sent, contents);
+    }
+
+    #[tokio::test]
+    async fn send_file_body_is_throttled_to_the_rate() {
+        // At 8000 bytes per second, the second 8000-byte chunk waits until a second has passed
+        let contents = vec![b'x'; 12_000];
+        let mut sent = Vec::new();
+        let started = Instant::now();
+        send_file_body(&mut sent, file_body("throttled", &contents).await, 8000).await.unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(sent.len(), contents.len());
+        assert!(elapsed >= Duration::from_millis(990), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn encoded_copies_older_than_the_file_are_skipped() {
+        let root = temp_root("stale-copy");
+        let encoded = temp_root("stale-copy-br");
+        fs::write(root.join("app.js"), "let fresh = true;").unwrap();
+        fs::write(encoded.join("app.js"), "outdated brotli").unwrap();
+        let source = fs::File::options().write(true).open(root.join("app.js")).unwrap();
+        source.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        let encoded_root = format!("br={}", encoded.display());
+        let headers = ["Accept-Encoding: br, gzip".to_string()];
+
+        // Falls back to compressing the file with --gzip, or to the plain file without it
+        let config = test_config(&root, &["--encoded-root", &encoded_root, "--gzip"]);
+        assert!(encoded_variant(&config, &root.join("app.js"), "br").is_none());
+        let response = handle_get_request(&root.join("app.js"), "HTTP/1.1", &headers, &config, &mut None, false).await.into_bytes();
+        assert!(String::from_utf8_lossy(&response).contains("Content-Encoding: gzip\r\n"));
+        let config = test_config(&root, &["--encoded-root", &encoded_root]);
+        let mut file_body = None;
+        let response = handle_get_request(&root.join("app.js"), "HTTP/1.1", &headers, &config, &mut file_body, false).await;
+        assert!(!String::from_utf8(response.into_bytes()).unwrap().contains("Content-Encoding"));
+        assert_eq!(file_body.unwrap().length, 17);
+
+        // Once the copy is newer again it is used
+        let copy = fs::File::options().write(true).open(encoded.join("app.js")).unwrap();
+        copy.set_modified(SystemTime::now() + Duration::from_secs(120)).unwrap();
+        assert!(encoded_variant(&config, &root.join("app.js"), "br").is_some());
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&encoded).unwrap();
+    }
+
+    // The Content-Location header of what handle_get_request answers for the file
+    async fn content_location_of(full_path: &Path, headers: &[&str], config: &Config, negotiated_index: bool) -> Option<String> {
+        let headers: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+        let head = handle_get_request(full_path, "HTTP/1.1", &headers, config, &mut None, negotiated_index).await.into_bytes();
+        let head = String::from_utf8_lossy(&head).into_owned();
+        head.lines().find_map(|line| line.strip_prefix("Content-Location: ")).map(str::to_string)
+    }
+
+    #[tokio::test]
+    async fn content_location_names_the_chosen_index_page_and_encoded_copy() {
+        let root = temp_root("content-location");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("br/docs")).unwrap();
+        fs::write(root.join("docs/index.html"), "<p>docs</p>").unwrap();
+        fs::write(root.join("docs/index.json"), "{}").unwrap();
+        fs::write(root.join("docs/guide.html"), "<p>guide</p>").unwrap();
+        fs::write(root.join("br/docs/guide.html"), "brotli").unwrap();
+        let encoded_root = format!("br={}", root.join("br").display());
+        let config = test_config(&root, &["--index-rule", "application/json=index.json", "--encoded-root", &encoded_root]);
+
+        // The index page chosen by a rule is named relative to the directory
+        let json = ["Accept: application/json"];
+        let index = index_page("GET", &root.join("docs"), &json.map(String::from), &config).unwrap();
+        assert_eq!(content_location_of(&index, &json, &config, true).await.as_deref(), Some("index.json"));
+        // Plain requests for a file name no other representation than the one requested
+        assert_eq!(content_location_of(&root.join("docs/guide.html"), &[], &config, false).await, None);
+        // An encoded copy inside the root folder is addressed by its own path
+        let brotli = ["Accept-Encoding: br"];
+        assert_eq!(content_location_of(&root.join("docs/guide.html"), &brotli, &config, false).await.as_deref(), Some("/br/docs/guide.html"));
+
+        // Outside the root folder the copy has no URL
+        let outside = temp_root("content-location-br");
+        fs::create_dir_all(outside.join("docs")).unwrap();
+        fs::write(outside.join("docs/guide.html"), "brotli").unwrap();
+        let config = test_config(&root, &["--encoded-root", &format!("br={}", outside.display())]);
+        assert_eq!(content_location_of(&root.join("docs/guide.html"), &brotli, &config, false).await, None);
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[tokio::test]
+    async fn problem_json_describes_a_missing_file() {
+        let root = temp_root("problem-json");
+        let config = test_config(&root, &["--error-format", "problem+json"]);
+        let response = with_error_body(&config, not_found_response(&config).await, Some("/missing%20page")).into_bytes();
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.contains("Content-Type: application/problem+json\r\n"));
+        assert!(head.ends_with(&format!("\r\nContent-Length: {}", body.len())));
+        let expected = r#"{"type":"about:blank","title":"Not Found","status":404,"detail":"No resource exists at this path.","instance":"/missing%20page"}"#;
+        assert_eq!(body, expected);
+
+        // The custom 404 page and successful responses are left as they are
+        fs::write(root.join("404.html"), "<p>gone</p>").unwrap();
+        let config = test_config(&root, &["--error-format", "problem+json", "--404-page"]);
+        let response = with_error_body(&config, not_found_response(&config).await, Some("/missing")).into_bytes();
+        assert!(response.ends_with(b"\r\n\r\n<p>gone</p>"));
+        assert_eq!(with_error_body(&config, Response::status(200, "OK"), Some("/")).into_bytes(), Response::status(200, "OK").into_bytes());
+        // Without the option errors stay empty
+        let config = test_config(&root, &[]);
+        assert!(with_error_body(&config, Response::status(404, "Not Found"), Some("/missing")).into_bytes().ends_with(b"Content-Length: 0\r\n\r\n"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // Serves a text file too large to compress in memory with --gzip, returning the head, the body sent after it and the file
+    async fn streamed_gzip(name: &str, version: &str) -> (String, Vec<u8>, Vec<u8>) {
+        let root = temp_root(name);
+        let contents: Vec<u8> = (0..3 * MAX_GZIP_SIZE).map(|byte| if byte % 80 == 79 { b'\n' } else { b'a' + (byte % 7) as u8 }).collect();
+        fs::write(root.join("large.txt"), &contents).unwrap();
+        let config = test_config(&root, &["--gzip"]);
+        let headers = ["Accept-Encoding: gzip".to_string()];
+        let mut file_body = None;
+        let response = handle_get_request(&root.join("large.txt"), version, &headers, &config, &mut file_body, false).await;
+        assert_eq!(response.ends_at_close(), version == "HTTP/1.0");
+        let head = String::from_utf8(response.into_bytes()).unwrap();
+        let mut body = Vec::new();
+        send_file_body(&mut body, file_body.unwrap(), 0).await.unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        (head, body, contents)
+    }
+
+    fn gunzip(compressed: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(compressed).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[tokio::test]
+    async fn large_files_are_compressed_while_they_are_sent_in_chunks() {
+        let (head, body, contents) = streamed_gzip("streamed-gzip", "HTTP/1.1").await;
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+        // Undo the chunked framing, then the compression
+        let mut compressed = Vec::new();
+        let mut rest = &body[..];
+        loop {
+            let line_end = rest.windows(2).position(|window| window == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                assert_eq!(rest, b"\r\n");
+                break;
+            }
+            compressed.extend_from_slice(&rest[..size]);
+            assert_eq!(&rest[size..size + 2], b"\r\n");
+            rest = &rest[size + 2..];
+        }
+        assert!(compressed.len() < contents.len() / 10);
+        assert!(gunzip(&compressed) == contents);
+    }
+
+    #[tokio::test]
+    async fn large_files_compressed_for_http_1_0_end_at_close() {
+        let (head, body, contents) = streamed_gzip("streamed-gzip-1-0", "HTTP/1.0").await;
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(!head.contains("Transfer-Encoding") && !head.contains("Content-Length"));
+        assert!(gunzip(&body) == contents);
+    }
+
+    // The server side of a connection on which a client sent these bytes and closed its side
+    async fn stream_with(sent: &'static [u8]) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(sent).await.unwrap();
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        client.await.unwrap();
+        stream
+    }
+
+    // Runs read_chunked_body on what a client sends after the head, returning the body and what was left over
+    async fn read_chunked(sent: &'static [u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        read_chunked_limited(sent, 0).await
+    }
+
+    async fn read_chunked_limited(sent: &'static [u8], max_body_size: u64) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut stream = stream_with(sent).await;
+        let mut pending = Vec::new();
+        let body = read_chunked_body(&mut stream, Vec::new(), &mut pending, max_body_size).await?;
+        Ok((body, pending))
+    }
+
+    #[tokio::test]
+    async fn read_chunked_body_stops_at_the_body_size_limit() {
+        let sent = b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        assert!(read_chunked_limited(sent, 11).await.is_ok());
+        let error = read_chunked_limited(sent, 10).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[tokio::test]
+    async fn read_request_refuses_declared_lengths_over_the_limit() {
+        // Refused from the head alone, the body is never waited for
+        let mut stream = stream_with(b"POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n").await;
+        let error = read_request(&mut stream, &mut Vec::new(), 1024).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        let mut stream = stream_with(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi").await;
+        let request = read_request(&mut stream, &mut Vec::new(), 2).await.unwrap().unwrap();
+        assert!(request.ends_with(b"\r\n\r\nhi"));
+    }
+
+    #[tokio::test]
+    async fn read_chunked_body_joins_chunks() {
+        let (body, pending) = read_chunked(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n").await.unwrap();
+        assert_eq!(body, b"hello world");
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_chunked_body_drops_trailers_and_keeps_the_next_request() {
+        let (body, pending) = read_chunked(b"a\r\n0123456789\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(body, b"0123456789");
+        assert_eq!(pending, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_chunked_body_rejects_malformed_chunks() {
+        assert!(read_chunked(b"zz\r\nhello\r\n0\r\n\r\n").await.is_err());
+        // The data is longer than the declared size
+        assert!(read_chunked(b"2\r\nhello\r\n0\r\n\r\n").await.is_err());
+        // The connection closes before the last chunk
+        assert!(read_chunked(b"5\r\nhello\r\n").await.is_err());
+    }
+
+    // Serves one connection with handle_client, returning the client's side of it
+    async fn client_of(config: Config) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            handle_client(stream, client_addr.ip(), &config).await;
+        });
+        TcpStream::connect(address).await.unwrap()
+    }
+
+    // Reads from the connection until what was received ends with the given bytes
+    async fn read_until(client: &mut TcpStream, end: &[u8]) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+        while !received.ends_with(end) {
+            let size = client.read(&mut chunk).await.unwrap();
+            assert!(size > 0, "closed after {:?}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&chunk[..size]);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn keep_alive_timeout_only_bounds_the_wait_for_the_next_request() {
+        let root = temp_root("keep-alive-wait");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let mut client = client_of(test_config(&root, &["--keep-alive-timeout", "1", "--request-timeout", "2"])).await;
+        client.write_all(b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        assert!(read_until(&mut client, b"\r\n\r\na").await.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // Once the next request started, taking longer than the keep-alive timeout to finish it is fine
+        client.write_all(b"GET /a.txt HTTP/1.1\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        client.write_all(b"Host: x\r\n\r\n").await.unwrap();
+        assert!(read_until(&mut client, b"\r\n\r\na").await.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // Past the request timeout it is answered like an unfinished first request
+        client.write_all(b"GET /a.txt HTTP/1.1\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(response.windows(19).any(|line| line == b"Connection: close\r\n"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn listings_only_close_the_connection_when_they_end_at_close() {
+        let root = temp_root("listing-framing");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/b.txt"), "b").unwrap();
+
+        // A chunked listing keeps the HTTP/1.1 connection open for the static file after it
+        let mut client = client_of(test_config(&root, &[])).await;
+        client.write_all(b"GET /dir/ HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let listing = read_until(&mut client, b"0\r\n\r\n").await;
+        assert!(String::from_utf8_lossy(&listing).contains("Connection: keep-alive\r\n"));
+        client.write_all(b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        assert!(read_until(&mut client, b"\r\n\r\na").await.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // For HTTP/1.0 the static file keeps the connection open, the listing after it ends at close
+        let mut client = client_of(test_config(&root, &[])).await;
+        client.write_all(b"GET /a.txt HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await.unwrap();
+        let file = read_until(&mut client, b"\r\n\r\na").await;
+        assert!(String::from_utf8_lossy(&file).contains("Connection: keep-alive\r\n"));
+        client.write_all(b"GET /dir/ HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await.unwrap();
+        let mut listing = Vec::new();
+        client.read_to_end(&mut listing).await.unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert!(listing.contains("Connection: close\r\n"));
+        assert!(!listing.contains("Transfer-Encoding") && !listing.contains("Content-Length"));
+        assert!(listing.contains("b.txt"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn gzipped_body(contents: &[u8]) -> (Vec<u8>, Vec<String>) {
+        (gzip(contents), vec!["Content-Encoding: gzip".to_string()])
+    }
+
+    #[test]
+    fn decodes_gzip_request_bodies_within_the_limits() {
+        let config = test_config(Path::new("/www"), &["--decompress-request-bodies"]);
+        let (body, headers) = gzipped_body(b"name=value");
+        assert_eq!(decode_request_body(&body, &headers, &config), Ok(Some(b"name=value".to_vec())));
+        // Without the option, or without a coding, the body is passed as it was sent
+        assert_eq!(decode_request_body(&body, &headers, &test_config(Path::new("/www"), &[])), Ok(None));
+        assert_eq!(decode_request_body(b"plain", &[], &config), Ok(None));
+    }
+
+    #[test]
+    fn request_bodies_expanding_past_the_ratio_are_too_large() {
+        let contents = vec![b'a'; 100_000];
+        let (body, headers) = gzipped_body(&contents);
+        let config = test_config(Path::new("/www"), &["--decompress-request-bodies", "--max-decompression-ratio", "10"]);
+        assert_eq!(decode_request_body(&body, &headers, &config), Err((413, "Payload Too Large")));
+        // The same body passes once the ratio allows it
+        let config = test_config(Path::new("/www"), &["--decompress-request-bodies", "--max-decompression-ratio", "0"]);
+        assert_eq!(decode_request_body(&body, &headers, &config), Ok(Some(contents)));
+    }
+
+    #[test]
+    fn request_bodies_decoding_past_the_absolute_limit_are_too_large() {
+        let (body, headers) = gzipped_body(&[b'a'; 2000]);
+        let config = test_config(Path::new("/www"), &["--decompress-request-bodies", "--max-decompressed-body", "1000", "--max-decompression-ratio", "0"]);
+        assert_eq!(decode_request_body(&body, &headers, &config), Err((413, "Payload Too Large")));
+        let (body, headers) = gzipped_body(&[b'a'; 1000]);
+        assert_eq!(decode_request_body(&body, &headers, &config).unwrap().map(|decoded| decoded.len()), Some(1000));
+    }
+
+    #[test]
+    fn corrupt_and_undecodable_request_bodies_are_rejected() {
+        let config = test_config(Path::new("/www"), &["--decompress-request-bodies"]);
+        let (mut body, headers) = gzipped_body(b"name=value");
+        body.truncate(body.len() / 2);
+        assert_eq!(decode_request_body(&body, &headers, &config), Err((400, "Bad Request")));
+        assert_eq!(decode_request_body(b"not gzip", &headers, &config), Err((400, "Bad Request")));
+        let brotli = ["Content-Encoding: br".to_string()];
+        assert_eq!(decode_request_body(b"\x0b\x02\x80", &brotli, &config), Err((415, "Unsupported Media Type")));
+        let stacked = ["Content-Encoding: gzip, gzip".to_string()];
+        assert_eq!(decode_request_body(&gzip(&gzip(b"x")), &stacked, &config), Err((415, "Unsupported Media Type")));
+    }
+
+    #[test]
+    fn identity_is_only_refused_explicitly() {
+        assert!(identity_refused("gzip, identity;q=0"));
+        assert!(identity_refused("br, IDENTITY; q=0.0"));
+        assert!(identity_refused("gzip, *;q=0"));
+        // Listed by name, identity isn't covered by the "*" refusal
+        assert!(!identity_refused("identity;q=0.5, *;q=0"));
+        assert!(!identity_refused("gzip"));
+        assert!(!identity_refused(""));
+    }
+
+    #[test]
+    fn only_chunked_and_identity_transfer_codings_are_supported() {
+        assert!(is_supported_transfer_encoding("chunked"));
+        assert!(is_supported_transfer_encoding("identity"));
+        assert!(is_supported_transfer_encoding("Identity, Chunked"));
+        assert!(!is_supported_transfer_encoding("gzip, chunked"));
+        assert!(!is_supported_transfer_encoding("chunked, br"));
+        assert!(!is_supported_transfer_encoding(""));
+    }
+
+    #[test]
+    fn the_most_specific_media_range_decides() {
+        assert!(accepts_media_type("application/json", "application/json"));
+        assert!(accepts_media_type("text/html, application/*;q=0.2", "application/json"));
+        assert!(accepts_media_type("*/*", "Application/JSON"));
+        assert!(!accepts_media_type("*/*, application/json;q=0", "application/json"));
+        assert!(accepts_media_type("application/*;q=0, application/json;q=0.1", "application/json"));
+        assert!(!accepts_media_type("application/*;q=0", "application/json"));
+        assert!(!accepts_media_type("text/html", "application/json"));
+    }
+
+    #[test]
+    fn tokens_are_rfc_7230_tchars() {
+        assert!(is_token("GET"));
+        assert!(is_token("PROPFIND"));
+        assert!(is_token("X-Custom_Header.v2"));
+        assert!(is_token("!#$%&'*+-.^_`|~"));
+        assert!(!is_token(""));
+        assert!(!is_token("GET /"));
+        assert!(!is_token("X:Y"));
+        assert!(!is_token("\"quoted\""));
+        assert!(!is_token("caf\u{e9}"));
+    }
+
+    #[test]
+    fn header_env_names_follow_cgi_and_the_denylist() {
+        let config = test_config(Path::new("/www"), &[]);
+        assert_eq!(header_env_name("Content-Type", &config).as_deref(), Some("HTTP_CONTENT_TYPE"));
+        assert_eq!(header_env_name("x_forwarded_for", &config).as_deref(), Some("HTTP_X_FORWARDED_FOR"));
+        // Names that aren't plain tokens can't produce arbitrary variables
+        assert_eq!(header_env_name("X-A=B", &config), None);
+        assert_eq!(header_env_name("", &config), None);
+        // Credentials are kept from scripts by default
+        assert_eq!(header_env_name("Authorization", &config), None);
+        assert_eq!(header_env_name("proxy-authorization", &config), None);
+        let config = test_config(Path::new("/www"), &["--script-header-deny", "Cookie"]);
+        assert_eq!(header_env_name("cookie", &config), None);
+        // An allowlist passes only what it names, even from the denylist
+        let config = test_config(Path::new("/www"), &["--script-header-allow", "Authorization"]);
+        assert_eq!(header_env_name("Authorization", &config).as_deref(), Some("HTTP_AUTHORIZATION"));
+        assert_eq!(header_env_name("Content-Type", &config), None);
+    }
+
+    #[test]
+    fn line_endings_are_normalized_for_scripts() {
+        assert_eq!(normalize_line_endings(b"a\r\nb\nc", LineEnding::Lf), b"a\nb\nc");
+        assert_eq!(normalize_line_endings(b"a\r\nb\nc", LineEnding::Crlf), b"a\r\nb\r\nc");
+        // Lone CRs aren't line endings
+        assert_eq!(normalize_line_endings(b"a\rb\r\r\n", LineEnding::Lf), b"a\rb\r\n");
+        assert_eq!(normalize_line_endings(b"", LineEnding::Crlf), b"");
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain name.txt"), "\"plain name.txt\"");
+        assert_eq!(json_string("say \"hi\"\\"), r#""say \"hi\"\\""#);
+        assert_eq!(json_string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(json_string("\u{1}\u{7f}"), r#""\u0001\u007f""#);
+        assert_eq!(json_string("caf\u{e9}"), "\"caf\u{e9}\"");
+    }
+
+    #[test]
+    fn etags_change_with_the_file_and_match_weakly() {
+        let root = temp_root("etag");
+        let path = root.join("page.html");
+        fs::write(&path, "first").unwrap();
+        let etag = file_etag(&fs::metadata(&path).unwrap(), None);
+        assert!(etag.starts_with("\"5-") && etag.ends_with('"'));
+        assert_eq!(etag, file_etag(&fs::metadata(&path).unwrap(), None));
+        let gzip_etag = file_etag(&fs::metadata(&path).unwrap(), Some("gzip"));
+        assert_eq!(gzip_etag, format!("{}-gzip\"", etag.trim_end_matches('"')));
+        fs::write(&path, "second").unwrap();
+        assert_ne!(etag, file_etag(&fs::metadata(&path).unwrap(), None));
+
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("\"other\", W/{}", etag), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(!etag_matches(&gzip_etag, &etag));
+        assert!(!etag_matches("\"abc\"", "\"abcd\""));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn mime_types_come_from_the_extension_in_any_case() {
+        assert_eq!(get_mime_type(Path::new("/www/app.mjs")), Some("text/javascript"));
+        assert_eq!(get_mime_type(Path::new("/www/module.wasm")), Some("application/wasm"));
+        assert_eq!(get_mime_type(Path::new("/www/photo.avif")), Some("image/avif"));
+        assert_eq!(get_mime_type(Path::new("/www/fonts/body.woff2")), Some("font/woff2"));
+        assert_eq!(get_mime_type(Path::new("/www/README.md")), Some("text/markdown"));
+        assert_eq!(get_mime_type(Path::new("/www/Photo.JpEg")), Some("image/jpeg"));
+        assert_eq!(get_mime_type(Path::new("/www/INDEX.HTML")), Some("text/html"));
+        // Only the last extension counts, and unknown or missing ones have no type
+        assert_eq!(get_mime_type(Path::new("/www/data.json.bak")), None);
+        assert_eq!(get_mime_type(Path::new("/www/Makefile")), None);
+    }
+
+    #[test]
+    fn required_accept_prefixes_match_whole_segments() {
+        let config = test_config(Path::new("/www"), &["--require-accept", "/api=application/json"]);
+        let html = ["Accept: text/html".to_string()];
+        assert!(!meets_required_accept(&config, "/api", &html));
+        assert!(!meets_required_accept(&config, "/api/users", &html));
+        assert!(meets_required_accept(&config, "/apidocs.html", &html));
+        assert!(meets_required_accept(&config, "/api/users", &["Accept: application/json".to_string()]));
+    }
+
+    #[test]
+    fn only_scripts_allow_post_patch_and_the_script_methods() {
+        let config = test_config(Path::new("/www"), &["--script-method", "PROPFIND"]);
+        assert_eq!(allowed_methods(&config, Path::new("/www/scripts/run.sh")), "GET, HEAD, OPTIONS, POST, PATCH, PROPFIND");
+        assert_eq!(allowed_methods(&config, Path::new("/www/index.html")), "GET, HEAD, OPTIONS");
+        assert_eq!(allowed_methods(&config, Path::new("/www/scriptsx/run.sh")), "GET, HEAD, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn options_advertises_accept_patch_on_configured_scripts() {
+        let root = temp_root("accept-patch");
+        fs::create_dir_all(root.join("scripts/api")).unwrap();
+        fs::write(root.join("scripts/api/edit.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let mut client = client_of(test_config(&root, &["--accept-patch", "/scripts/api=application/merge-patch+json"])).await;
+
+        client.write_all(b"OPTIONS /scripts/api/edit.sh HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let response = String::from_utf8(read_until(&mut client, b"\r\n\r\n").await).unwrap();
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS, POST, PATCH\r\n"));
+        assert!(response.contains("Accept-Patch: application/merge-patch+json\r\n"));
+
+        // Static files take neither POST, PATCH nor patch documents
+        client.write_all(b"OPTIONS /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let response = String::from_utf8(read_until(&mut client, b"\r\n\r\n").await).unwrap();
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+        assert!(!response.contains("Accept-Patch"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // The server side of a connection, and the client's side of it
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        (stream, client)
+    }
+
+    #[tokio::test]
+    async fn a_failed_send_closes_the_connection() {
+        let root = temp_root("failed-send");
+        fs::write(root.join("large.bin"), vec![0u8; 16 * 1024 * 1024]).unwrap();
+        let config = test_config(&root, &[]);
+        let (mut stream, mut client) = connection().await;
+        // Closing with unread data resets the connection, so the body can't be sent
+        client.write_all(b"ping").await.unwrap();
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let raw = b"GET /large.bin HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(!handle_request(&mut stream, Ipv4Addr::LOCALHOST.into(), raw, true, &config).await);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_unsupported_transfer_coding_closes_the_connection() {
+        let root = temp_root("unknown-length");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let mut client = client_of(test_config(&root, &[])).await;
+        // Where a gzip-coded body ends is unknown, so the next request can't be found
+        client.write_all(b"POST /a.txt HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\n\r\nbody").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_1_0_listings_close_the_connection() {
+        let root = temp_root("listing-close");
+        fs::create_dir(root.join("dir")).unwrap();
+        let config = test_config(&root, &[]);
+        let (mut stream, mut client) = connection().await;
+        for method in ["HEAD", "GET"] {
+            let raw = format!("{} /dir/ HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", method);
+            assert!(!handle_request(&mut stream, Ipv4Addr::LOCALHOST.into(), raw.as_bytes(), true, &config).await);
+        }
+        drop(stream);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8(response).unwrap().matches("Connection: close\r\n").count(), 2);
+        // An HTTP/1.1 listing is chunked, so the connection stays open
+        let (mut stream, _client) = connection().await;
+        let raw = b"GET /dir/ HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(handle_request(&mut stream, Ipv4Addr::LOCALHOST.into(), raw, true, &config).await);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_kept_alive_connections_are_closed_after_the_keep_alive_timeout() {
+        let root = temp_root("keep-alive-idle");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let mut client = client_of(test_config(&root, &["--keep-alive-timeout", "1"])).await;
+        client.write_all(b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        read_until(&mut client, b"\r\n\r\na").await;
+        let started = Instant::now();
+        // Nothing more is sent, the server closes the connection without an answer
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert!(started.elapsed() < Duration::from_millis(1500), "took {:?}", started.elapsed());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}